@@ -0,0 +1,82 @@
+/*
+ * C and C++ interface of libsftrace.so, for programs built with
+ * `clang -fxray-instrument`.
+ *
+ *     int main(void) {
+ *         sftrace_setup_main();
+ *         ...
+ *     }
+ *
+ * Link with `-L"$TOPATH" -lsftrace` and run with `SFTRACE_OUTPUT_FILE` set,
+ * the trace is converted with `sftrace convert` as usual.
+ *
+ * On Linux libsftrace.so also defines `pthread_create`, to record which thread
+ * spawned each one. It takes over thread creation in the whole program and
+ * calls straight through to the libc one when nothing is recorded.
+ */
+
+#ifndef SFTRACE_H
+#define SFTRACE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Patch the xray sleds and start recording.
+ *
+ * The slots locate the object to patch, pass NULL to patch the main executable.
+ * This rewrites the text segment, so call it before any other thread is started.
+ * Nothing is recorded unless `SFTRACE_OUTPUT_FILE`, `SFTRACE_OUTPUT_DIR`
+ * or `SFTRACE_CONFIG` is set.
+ */
+void sftrace_setup(void (*entry_slot)(void), void (*exit_slot)(void),
+                   void (*tailcall_slot)(void));
+
+static inline void sftrace_setup_main(void) {
+    sftrace_setup(NULL, NULL, NULL);
+}
+
+#define SFTRACE_ALLOC 1
+#define SFTRACE_DEALLOC 2
+#define SFTRACE_REALLOC_ALLOC 3
+#define SFTRACE_REALLOC_DEALLOC 4
+
+/*
+ * Record an allocation event for `sftrace memory`, from your allocator wrappers.
+ * A realloc is a SFTRACE_REALLOC_DEALLOC of the old pointer
+ * followed by a SFTRACE_REALLOC_ALLOC of the new one.
+ */
+void sftrace_alloc_event(uint8_t kind, size_t size, size_t align, void *ptr);
+
+/*
+ * Enable or disable recording on the current thread,
+ * this takes precedence over the thread filters.
+ */
+void sftrace_set_thread_enabled(bool enabled);
+
+struct sftrace_frame {
+    /* the entry sled id, as in the trace */
+    uint32_t func_id;
+    /* runtime address of the function */
+    uintptr_t addr;
+};
+
+/*
+ * Copy the instrumented functions the current thread is in, outermost first,
+ * into `frames` and return the depth, which may be larger than `cap`.
+ */
+size_t sftrace_current_stack(struct sftrace_frame *frames, size_t cap);
+
+/* Runtime address of a symbol of the patched object, 0 if not found. */
+uintptr_t sftrace_symbol_address(const char *name);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SFTRACE_H */
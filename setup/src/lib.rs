@@ -8,6 +8,20 @@ unsafe extern "C" {
     );
 
     fn sftrace_alloc_event(kind: u8, size: usize, align: usize, ptr: *mut u8);
+
+    fn sftrace_unwind_event();
+
+    fn sftrace_set_thread_enabled(enabled: bool);
+
+    fn sftrace_current_stack(frames: *mut Frame, cap: usize) -> usize;
+
+    fn sftrace_on_entry(addr: usize, hook: Option<EntryHook>) -> usize;
+
+    fn sftrace_on_exit(addr: usize, hook: Option<ExitHook>) -> usize;
+
+    fn sftrace_symbol_address(name: *const std::ffi::c_char) -> usize;
+
+    fn sftrace_on_slow(callback: Option<SlowCallback>);
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -63,9 +77,17 @@ build_slot! {
     sftrace_tailcall_slot;
 }
 
+/// Patch the xray sleds and start recording.
+///
+/// # Safety
+///
+/// This rewrites the text segment, so it must be called before any other thread is started.
 #[inline(always)]
 pub unsafe fn setup() {
-    if std::env::var_os("SFTRACE_OUTPUT_FILE").is_none() {
+    if std::env::var_os("SFTRACE_OUTPUT_FILE").is_none()
+        && std::env::var_os("SFTRACE_OUTPUT_DIR").is_none()
+        && std::env::var_os("SFTRACE_CONFIG").is_none()
+    {
         // Not enabled, ignored
         return;
     }
@@ -75,6 +97,229 @@ pub unsafe fn setup() {
     unsafe {
         sftrace_setup(sftrace_entry_slot, sftrace_exit_slot, sftrace_tailcall_slot);
     }
+
+    // Unwinding skips the exit sleds of every frame it passes through,
+    // so leave a marker for the converter to close them.
+    // Only once, a second hook would record every panic twice.
+    static PANIC_HOOK: std::sync::Once = std::sync::Once::new();
+    PANIC_HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            hook(info);
+
+            unsafe {
+                sftrace_unwind_event();
+            }
+        }));
+    });
+}
+
+/// Enable or disable recording on the current thread.
+///
+/// This takes precedence over `SFTRACE_SETUP_THREAD_ONLY` and `SFTRACE_THREAD_FILTER`.
+pub fn set_thread_enabled(enabled: bool) {
+    unsafe {
+        sftrace_set_thread_enabled(enabled);
+    }
+}
+
+/// An instrumented function on the shadow stack.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The xray function id, as in the trace.
+    pub func_id: u32,
+    /// Runtime address of the function, symbolize it against the loaded binary.
+    pub addr: usize,
+}
+
+/// The instrumented functions the current thread is in, outermost first.
+///
+/// This is kept by the recorder on every entry and exit, so it is cheap and needs no unwinding,
+/// but only covers the patched functions. Empty if tracing is not enabled.
+pub fn current_stack() -> Vec<Frame> {
+    let mut stack = Vec::<Frame>::with_capacity(64);
+    loop {
+        let cap = stack.capacity();
+        let len = unsafe { sftrace_current_stack(stack.as_mut_ptr(), cap) };
+        if len <= cap {
+            unsafe {
+                stack.set_len(len);
+            }
+            return stack;
+        }
+
+        stack.reserve(len);
+    }
+}
+
+/// Registers saved at a function entry, the argument registers of the platform.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct Args {
+    pub r11: u64,
+    pub r10: u64,
+
+    pub r9: u64,
+    pub r8: u64,
+    pub rcx: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rdi: u64,
+
+    pub xmm7: u128,
+    pub xmm6: u128,
+    pub xmm5: u128,
+    pub xmm4: u128,
+    pub xmm3: u128,
+    pub xmm2: u128,
+    pub xmm1: u128,
+    pub xmm0: u128,
+}
+
+/// Registers saved at a function exit, the return value registers of the platform.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct ReturnValue {
+    pub rax: u64,
+    pub rdx: u64,
+    pub xmm0: u128,
+    pub xmm1: u128,
+}
+
+/// Registers saved at a function entry, the argument registers of the platform.
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct Args {
+    pub x8: u64,
+    pub x0: u64,
+
+    pub q6: u128,
+    pub q7: u128,
+    pub q4: u128,
+    pub q5: u128,
+    pub q2: u128,
+    pub q3: u128,
+    pub q0: u128,
+    pub q1: u128,
+
+    pub x7: u64,
+    pub x30: u64,
+    pub x5: u64,
+    pub x6: u64,
+    pub x3: u64,
+    pub x4: u64,
+    pub x1: u64,
+    pub x2: u64,
+}
+
+/// Registers saved at a function exit, the return value registers of the platform.
+#[cfg(target_arch = "aarch64")]
+pub type ReturnValue = Args;
+
+pub type EntryHook = extern "C" fn(func_id: u32, args: &Args);
+pub type ExitHook = extern "C" fn(func_id: u32, return_value: &ReturnValue);
+
+/// A function to hook, by symbol name or by address.
+#[derive(Clone, Copy, Debug)]
+pub enum Function<'a> {
+    /// The exact symbol name, mangled.
+    Symbol(&'a str),
+    /// The runtime address, such as `my_function as usize`.
+    Addr(usize),
+}
+
+impl<'a> From<&'a str> for Function<'a> {
+    fn from(name: &'a str) -> Self {
+        Function::Symbol(name)
+    }
+}
+
+impl From<usize> for Function<'_> {
+    fn from(addr: usize) -> Self {
+        Function::Addr(addr)
+    }
+}
+
+impl Function<'_> {
+    fn address(self) -> Option<usize> {
+        match self {
+            Function::Addr(addr) => Some(addr),
+            Function::Symbol(name) => {
+                let name = std::ffi::CString::new(name).ok()?;
+                let addr = unsafe { sftrace_symbol_address(name.as_ptr()) };
+                (addr != 0).then_some(addr)
+            }
+        }
+    }
+}
+
+/// Run `hook` after every entry into the function, alongside recording it.
+///
+/// Returns false if the function is not instrumented or tracing is not enabled.
+/// The hook runs on the traced thread, so it should be quick and must not panic.
+pub fn on_entry<'a>(function: impl Into<Function<'a>>, hook: EntryHook) -> bool {
+    function
+        .into()
+        .address()
+        .is_some_and(|addr| unsafe { sftrace_on_entry(addr, Some(hook)) } != 0)
+}
+
+/// Run `hook` before every exit from the function, alongside recording it.
+///
+/// Exits through a tail call don't run it, as there is no return value.
+pub fn on_exit<'a>(function: impl Into<Function<'a>>, hook: ExitHook) -> bool {
+    function
+        .into()
+        .address()
+        .is_some_and(|addr| unsafe { sftrace_on_exit(addr, Some(hook)) } != 0)
+}
+
+/// Remove the entry and exit hooks of the function.
+pub fn remove_hooks<'a>(function: impl Into<Function<'a>>) {
+    if let Some(addr) = function.into().address() {
+        unsafe {
+            sftrace_on_entry(addr, None);
+            sftrace_on_exit(addr, None);
+        }
+    }
+}
+
+/// A call that took longer than its `SFTRACE_SLOW` budget.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SlowCall {
+    /// The xray function id of the entry, as in the trace.
+    pub func_id: u32,
+    /// The sftrace thread id, `u32::MAX` if the thread has not recorded anything.
+    pub tid: u32,
+    pub duration_ns: u64,
+    pub budget_ns: u64,
+    name: *const std::ffi::c_char,
+}
+
+impl SlowCall {
+    /// The demangled function name.
+    pub fn name(&self) -> &str {
+        unsafe { std::ffi::CStr::from_ptr(self.name) }
+            .to_str()
+            .unwrap_or_default()
+    }
+}
+
+pub type SlowCallback = extern "C" fn(&SlowCall);
+
+/// Call `callback` on every slow call instead of printing it to stderr.
+///
+/// It runs on the thread of the call, right after the function returns.
+pub fn on_slow(callback: SlowCallback) {
+    unsafe {
+        sftrace_on_slow(Some(callback));
+    }
 }
 
 static ENABLE_ALLOCATOR_HOOK: std::sync::atomic::AtomicBool =
@@ -196,15 +196,27 @@ build!(entry   : xray_entry     -> events::record_entry);
 build!(exit    : xray_exit      -> events::record_exit);
 build!(tailcall: xray_tailcall  -> events::record_tailcall);
 
+pub(crate) const SLED_SIZE: usize = 8 * 4;
+
+const B_32: u32 = 0x14000008; // B #32
+
+/// Check that `address` holds an unpatched sled, `b #32` followed by 7 nops.
+pub(crate) unsafe fn check_sled(address: usize, _kind: u8) -> bool {
+    const NOP: u32 = 0xD503201F;
+
+    let sled = unsafe { std::slice::from_raw_parts(address as *const u32, SLED_SIZE / 4) };
+
+    sled[0] == B_32 && sled[1..].iter().all(|&inst| inst == NOP)
+}
+
 // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.5/compiler-rt/lib/xray/xray_AArch64.cpp#L33
-unsafe fn patch_sled(address: usize, idx: u32, slot: unsafe extern "C" fn()) {
+unsafe fn patch_sled(address: usize, idx: u32, slot: usize) {
     const STP_X0_X30_SP_M16E: u32 = 0xA9BF7BE0; // STP X0, X30, [SP, #-16]!
     const LDR_W17_12: u32 = 0x18000071; // LDR w17, #12
     const LDR_X16_12: u32 = 0x58000070; // LDR x16, #12
     const BLR_X16: u32 = 0xD63F0200; // BLR ip0
     const LDP_X0_X30_SP_16: u32 = 0xA8C17BE0; // LDP X0, X30, [SP], #16
 
-    let trampoline = slot as usize;
     let addr = ptr::null_mut::<u32>().with_addr(address);
 
     unsafe {
@@ -212,7 +224,7 @@ unsafe fn patch_sled(address: usize, idx: u32, slot: unsafe extern "C" fn()) {
         addr.add(2).write(LDR_X16_12);
         addr.add(3).write(BLR_X16);
         addr.add(4).write(idx);
-        addr.add(5).cast::<u64>().write(trampoline as u64);
+        addr.add(5).cast::<u64>().write(slot as u64);
         addr.add(7).write(LDP_X0_X30_SP_16);
         AtomicU32::from_ptr(addr.cast()).store(STP_X0_X30_SP_M16E, atomic::Ordering::Release);
     }
@@ -222,20 +234,48 @@ unsafe fn patch_sled(address: usize, idx: u32, slot: unsafe extern "C" fn()) {
     }
 }
 
-pub(crate) unsafe fn patch_entry(address: usize, func_id: u32, slot: unsafe extern "C" fn()) {
+pub(crate) unsafe fn patch_entry(address: usize, func_id: u32, slot: usize) {
     unsafe {
         patch_sled(address, func_id, slot);
     }
 }
 
-pub(crate) unsafe fn patch_exit(address: usize, func_id: u32, slot: unsafe extern "C" fn()) {
+pub(crate) unsafe fn patch_exit(address: usize, func_id: u32, slot: usize) {
     unsafe {
         patch_sled(address, func_id, slot);
     }
 }
 
-pub(crate) unsafe fn patch_tailcall(address: usize, func_id: u32, slot: unsafe extern "C" fn()) {
+pub(crate) unsafe fn patch_tailcall(address: usize, func_id: u32, slot: usize) {
     unsafe {
         patch_sled(address, func_id, slot);
     }
 }
+
+/// Restore the `b #32`, the rest of the sled is left as is for threads still inside it.
+unsafe fn unpatch_sled(address: usize) {
+    let addr = ptr::null_mut::<u32>().with_addr(address);
+
+    unsafe {
+        AtomicU32::from_ptr(addr).store(B_32, atomic::Ordering::Release);
+        clear_cache::clear_cache(addr, addr.add(1));
+    }
+}
+
+pub(crate) unsafe fn unpatch_entry(address: usize) {
+    unsafe {
+        unpatch_sled(address);
+    }
+}
+
+pub(crate) unsafe fn unpatch_exit(address: usize) {
+    unsafe {
+        unpatch_sled(address);
+    }
+}
+
+pub(crate) unsafe fn unpatch_tailcall(address: usize) {
+    unsafe {
+        unpatch_sled(address);
+    }
+}
@@ -4,7 +4,7 @@ use crate::events;
 use crate::util::{u64_is_zero, u128_is_zero};
 use serde::Serialize;
 use std::ptr;
-use std::sync::atomic::{self, AtomicU16, AtomicU64};
+use std::sync::atomic::{self, AtomicU8, AtomicU16, AtomicU64};
 
 #[derive(Serialize)]
 #[repr(C)]
@@ -202,6 +202,31 @@ build!(entry: xray_entry     -> events::record_entry);
 build!(exit : xray_exit      -> events::record_exit);
 build!(entry: xray_tailcall  -> events::record_tailcall);
 
+pub(crate) const SLED_SIZE: usize = 11;
+
+/// Check that `address` holds an unpatched sled of `kind`,
+/// `jmp +9` (entry, tail call) or `ret` (exit) followed by nops.
+pub(crate) unsafe fn check_sled(address: usize, kind: u8) -> bool {
+    const JMP_9: [u8; 2] = [0xeb, 0x09];
+    const RET: u8 = 0xc3;
+
+    let sled = unsafe { std::slice::from_raw_parts(address as *const u8, SLED_SIZE) };
+    let nops = match kind {
+        0 | 2 => sled.strip_prefix(&JMP_9),
+        1 => sled.strip_prefix(&[RET]),
+        _ => None,
+    };
+
+    nops.is_some_and(|mut nops| {
+        // operand size and segment prefixes of multi-byte nops
+        while let [0x66 | 0x2e, rest @ ..] = nops {
+            nops = rest;
+        }
+
+        matches!(nops, [0x90, ..] | [0x0f, 0x1f, ..])
+    })
+}
+
 pub(crate) unsafe fn patch_slot(slot: *mut u8, target: usize) {
     const JMP_QPTR_RIP1: u64 = 0xcc0000000125ff3e;
 
@@ -212,13 +237,11 @@ pub(crate) unsafe fn patch_slot(slot: *mut u8, target: usize) {
 }
 
 // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/compiler-rt/lib/xray/xray_x86_64.cpp#L123
-pub(crate) unsafe fn patch_entry(address: usize, idx: u32, slot: unsafe extern "C" fn()) {
+pub(crate) unsafe fn patch_entry(address: usize, idx: u32, slot: usize) {
     const CALL_OP_CODE: u8 = 0xe8;
     const MOV_R10_SEQ: u16 = 0xba41;
 
-    let trampoline = slot as usize;
-
-    let offset = (trampoline as isize) - (address + 11) as isize;
+    let offset = (slot as isize) - (address + 11) as isize;
     let offset = offset.try_into().unwrap();
 
     let addr = ptr::null_mut::<u8>().with_addr(address);
@@ -231,13 +254,11 @@ pub(crate) unsafe fn patch_entry(address: usize, idx: u32, slot: unsafe extern "
     }
 }
 
-pub(crate) unsafe fn patch_exit(address: usize, func_id: u32, slot: unsafe extern "C" fn()) {
+pub(crate) unsafe fn patch_exit(address: usize, func_id: u32, slot: usize) {
     const JMP_OP_CODE: u8 = 0xe9;
     const MOV_R10_SEQ: u16 = 0xba41;
 
-    let trampoline = slot as usize;
-
-    let offset = (trampoline as isize) - (address + 11) as isize;
+    let offset = (slot as isize) - (address + 11) as isize;
     let offset = offset.try_into().unwrap();
 
     let addr = ptr::null_mut::<u8>().with_addr(address);
@@ -251,8 +272,35 @@ pub(crate) unsafe fn patch_exit(address: usize, func_id: u32, slot: unsafe exter
 }
 
 // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/compiler-rt/lib/xray/xray_x86_64.cpp#L224
-pub(crate) unsafe fn patch_tailcall(address: usize, func_id: u32, slot: unsafe extern "C" fn()) {
+pub(crate) unsafe fn patch_tailcall(address: usize, func_id: u32, slot: usize) {
     unsafe {
         patch_entry(address, func_id, slot);
     }
 }
+
+/// Restore the `jmp +9`, the rest of the sled is left as is for threads still inside it.
+pub(crate) unsafe fn unpatch_entry(address: usize) {
+    const JMP_9: u16 = 0x09eb;
+
+    let addr = ptr::null_mut::<u8>().with_addr(address);
+
+    unsafe {
+        AtomicU16::from_ptr(addr.cast()).store(JMP_9, atomic::Ordering::Release);
+    }
+}
+
+pub(crate) unsafe fn unpatch_exit(address: usize) {
+    const RET: u8 = 0xc3;
+
+    let addr = ptr::null_mut::<u8>().with_addr(address);
+
+    unsafe {
+        AtomicU8::from_ptr(addr).store(RET, atomic::Ordering::Release);
+    }
+}
+
+pub(crate) unsafe fn unpatch_tailcall(address: usize) {
+    unsafe {
+        unpatch_entry(address);
+    }
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Deserializer, de};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs};
+
+/// Capture config, loaded from `SFTRACE_CONFIG`.
+///
+/// The `SFTRACE_*` environment variables take precedence over the file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// print a summary of the patched sleds at setup
+    pub verbose: bool,
+    pub output: Output,
+    pub filter: Filter,
+    pub record: Record,
+    pub thread: Thread,
+    pub object: Object,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    /// path template, see `Output::path`
+    pub file: Option<PathBuf>,
+    pub dir: Option<PathBuf>,
+    #[serde(deserialize_with = "de_size")]
+    pub buffer_size: Option<u64>,
+    #[serde(deserialize_with = "de_size")]
+    pub max_size: Option<u64>,
+    #[serde(deserialize_with = "de_size")]
+    pub rotate_size: Option<u64>,
+    /// hand over the thread buffers at least this often
+    #[serde(deserialize_with = "de_duration")]
+    pub flush_interval: Option<Duration>,
+    /// write out the buffers on SIGSEGV, SIGBUS, SIGABRT and SIGILL
+    pub crash_handler: bool,
+}
+
+impl Output {
+    const DEFAULT_NAME: &str = "sf-%e-%p.log";
+
+    /// The output path template, `file` is relative to `dir` if both are set.
+    ///
+    /// `%p` pid, `%t` start time, `%e` executable name, `%h` hostname,
+    /// `%n` the first sequence number that is not taken, `%%` a `%`.
+    pub fn path(&self) -> Option<PathBuf> {
+        match (self.dir.as_ref(), self.file.as_ref()) {
+            (Some(dir), Some(file)) => Some(dir.join(file)),
+            (Some(dir), None) => Some(dir.join(Self::DEFAULT_NAME)),
+            (None, file) => file.cloned(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Record {
+    pub cpu_time: bool,
+    /// record the CPU each thread runs on, when it changes
+    pub cpu: bool,
+    pub sw_counters: Vec<String>,
+    pub clock: Clock,
+    /// record the arguments and return value of these symbols,
+    /// raw names or demangled Rust paths without the hash, like `demo::leaf`
+    pub args: Vec<String>,
+    /// only record while inside these symbols, named like `args`
+    pub triggers: Vec<String>,
+    /// `<regex>:<duration>`, report the calls of matching functions over the duration
+    pub slow: Vec<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thread {
+    /// only record the thread that called `sftrace_setup`
+    pub setup_only: bool,
+    /// only record the threads whose name matches this regex
+    pub filter: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Object {
+    /// debug symbol path used by convert
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Clock {
+    /// monotonic, relative to the first event
+    #[default]
+    Relative,
+    Monotonic,
+    Boottime,
+    Realtime,
+}
+
+impl Clock {
+    pub fn parse(s: &str) -> Option<Clock> {
+        match s {
+            "relative" => Some(Clock::Relative),
+            "monotonic" => Some(Clock::Monotonic),
+            "boottime" => Some(Clock::Boottime),
+            "realtime" => Some(Clock::Realtime),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let buf = fs::read_to_string(path)
+            .map_err(|err| anyhow::format_err!("read config {:?} failed: {:?}", path, err))?;
+        let config = toml::from_str(&buf)
+            .map_err(|err| anyhow::format_err!("parse config {:?} failed: {}", path, err))?;
+        Ok(config)
+    }
+
+    /// Load `SFTRACE_CONFIG` if set and apply the environment variables over it.
+    pub fn from_env() -> anyhow::Result<Config> {
+        let config = match env::var_os("SFTRACE_CONFIG") {
+            Some(path) if !path.is_empty() => Config::load(Path::new(&path))?,
+            _ => Config::default(),
+        };
+
+        config.with_env()
+    }
+
+    /// Apply the `SFTRACE_*` environment variables over this config.
+    pub fn with_env(mut self) -> anyhow::Result<Config> {
+        let config = &mut self;
+
+        if let Ok(key) = env::var("SFTRACE_VERBOSE") {
+            config.verbose = !key.is_empty() && key != "0";
+        }
+
+        if let Some(path) = env::var_os("SFTRACE_OUTPUT_FILE") {
+            config.output.file = Some(path.into());
+        }
+
+        if let Some(path) = env::var_os("SFTRACE_OUTPUT_DIR") {
+            config.output.dir = Some(path.into());
+        }
+
+        if let Some(path) = env::var_os("SFTRACE_FILTER") {
+            config.filter.path = Some(path.into());
+        }
+
+        if let Ok(key) = env::var("SFTRACE_SETUP_THREAD_ONLY")
+            && !key.is_empty()
+        {
+            config.thread.setup_only = true;
+        }
+
+        if let Ok(filter) = env::var("SFTRACE_THREAD_FILTER")
+            && !filter.is_empty()
+        {
+            config.thread.filter = Some(filter);
+        }
+
+        if let Ok(key) = env::var("SFTRACE_CRASH_HANDLER")
+            && !key.is_empty()
+        {
+            config.output.crash_handler = true;
+        }
+
+        if let Ok(key) = env::var("SFTRACE_CPU_TIME")
+            && !key.is_empty()
+        {
+            config.record.cpu_time = true;
+        }
+
+        if let Ok(key) = env::var("SFTRACE_RECORD_CPU") {
+            config.record.cpu = !key.is_empty() && key != "0";
+        }
+
+        if let Ok(list) = env::var("SFTRACE_SW_COUNTERS") {
+            config.record.sw_counters = list
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        if let Ok(rules) = env::var("SFTRACE_SLOW") {
+            config.record.slow = rules
+                .split(';')
+                .map(str::trim)
+                .filter(|rule| !rule.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        if let Ok(clock) = env::var("SFTRACE_CLOCK") {
+            config.record.clock = Clock::parse(&clock)
+                .ok_or_else(|| anyhow::format_err!("bad SFTRACE_CLOCK: {:?}", clock))?;
+        }
+
+        for (name, size) in [
+            ("SFTRACE_BUFFER_SIZE", &mut config.output.buffer_size),
+            ("SFTRACE_MAX_SIZE", &mut config.output.max_size),
+            ("SFTRACE_ROTATE_SIZE", &mut config.output.rotate_size),
+        ] {
+            if let Ok(value) = env::var(name)
+                && !value.is_empty()
+            {
+                let value = parse_size(&value)
+                    .ok_or_else(|| anyhow::format_err!("bad {}: {:?}", name, value))?;
+                *size = Some(value);
+            }
+        }
+
+        if let Ok(value) = env::var("SFTRACE_FLUSH_INTERVAL")
+            && !value.is_empty()
+        {
+            let interval = parse_duration(&value)
+                .ok_or_else(|| anyhow::format_err!("bad SFTRACE_FLUSH_INTERVAL: {:?}", value))?;
+            config.output.flush_interval = Some(interval);
+        }
+
+        Ok(self)
+    }
+}
+
+/// Parse sizes like `4096`, `64k`, `1M`.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parse durations like `500ms`, `10s`, `2m`, a bare number is in seconds.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num = num.parse::<f64>().ok()?;
+    let scale = match unit {
+        "ns" => 1e-9,
+        "us" => 1e-6,
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+
+    Duration::try_from_secs_f64(num * scale).ok()
+}
+
+fn de_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Int(u64),
+        Str(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Int(n) => Ok(Some(n)),
+        Size::Str(s) => parse_size(&s)
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("bad size: {:?}", s))),
+    }
+}
+
+fn de_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("bad duration: {:?}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 * 1024));
+        assert_eq!(parse_size("1M"), Some(1024 * 1024));
+        assert_eq!(parse_size(" 2g "), Some(2 << 30));
+        assert_eq!(parse_size("99999999999G"), None);
+        assert_eq!(parse_size("1T"), None);
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("10us"), Some(Duration::from_micros(10)));
+        assert_eq!(parse_duration("10d"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn test_de_size_duration() {
+        let config: Config = toml::from_str(
+            r#"
+            [output]
+            buffer_size = "64k"
+            max_size = 1048576
+            flush_interval = "500ms"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.output.buffer_size, Some(64 * 1024));
+        assert_eq!(config.output.max_size, Some(1024 * 1024));
+        assert_eq!(
+            config.output.flush_interval,
+            Some(Duration::from_millis(500))
+        );
+
+        assert!(toml::from_str::<Config>("output.buffer_size = \"64x\"").is_err());
+        assert!(toml::from_str::<Config>("output.flush_interval = \"5 days\"").is_err());
+    }
+}
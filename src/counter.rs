@@ -0,0 +1,191 @@
+use crate::layout;
+use std::sync::atomic::{self, AtomicBool, AtomicU8};
+
+pub static CPU_TIME: AtomicBool = AtomicBool::new(false);
+pub static SW_COUNTERS: AtomicU8 = AtomicU8::new(0);
+pub static RECORD_CPU: AtomicBool = AtomicBool::new(false);
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy)]
+    pub struct CounterFlag: u8 {
+        const CTX_SWITCHES  = 0b00000001;
+        const PAGE_FAULTS   = 0b00000010;
+    }
+}
+
+impl CounterFlag {
+    pub fn parse(names: &[String]) -> CounterFlag {
+        let mut flag = CounterFlag::empty();
+
+        for name in names {
+            match name.as_str() {
+                "ctx" => flag |= CounterFlag::CTX_SWITCHES,
+                "faults" => flag |= CounterFlag::PAGE_FAULTS,
+                name => eprintln!("unsupported counter: {:?}", name),
+            }
+        }
+
+        flag
+    }
+
+    pub fn current() -> CounterFlag {
+        CounterFlag::from_bits_truncate(SW_COUNTERS.load(atomic::Ordering::Relaxed))
+    }
+}
+
+pub fn thread_cpu_time() -> Option<u64> {
+    if !CPU_TIME.load(atomic::Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+
+    unsafe {
+        if libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) != 0 {
+            return None;
+        }
+    }
+
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+/// The CPU the current thread runs on
+pub fn current_cpu() -> Option<u32> {
+    if !RECORD_CPU.load(atomic::Ordering::Relaxed) {
+        return None;
+    }
+
+    // vDSO, reads the cpu number with rdtscp or rdpid on x86_64
+    #[cfg(target_os = "linux")]
+    let cpu = u32::try_from(unsafe { libc::sched_getcpu() }).ok();
+
+    #[cfg(not(target_os = "linux"))]
+    let cpu = None;
+
+    cpu
+}
+
+/// Per thread software counters
+#[derive(Default)]
+pub struct Counters {
+    ctx_switches: Option<PerfEvent>,
+    page_faults: Option<PerfEvent>,
+}
+
+impl Counters {
+    pub fn open(flag: CounterFlag) -> Counters {
+        Counters {
+            ctx_switches: flag
+                .contains(CounterFlag::CTX_SWITCHES)
+                .then(|| PerfEvent::open(PERF_COUNT_SW_CONTEXT_SWITCHES))
+                .flatten(),
+            page_faults: flag
+                .contains(CounterFlag::PAGE_FAULTS)
+                .then(|| PerfEvent::open(PERF_COUNT_SW_PAGE_FAULTS))
+                .flatten(),
+        }
+    }
+
+    pub fn read(&self) -> Option<layout::Counters> {
+        if self.ctx_switches.is_none() && self.page_faults.is_none() {
+            return None;
+        }
+
+        Some(layout::Counters {
+            ctx_switches: self.ctx_switches.as_ref().and_then(PerfEvent::read),
+            page_faults: self.page_faults.as_ref().and_then(PerfEvent::read),
+        })
+    }
+}
+
+// https://github.com/torvalds/linux/blob/v6.14/include/uapi/linux/perf_event.h
+#[cfg(target_os = "linux")]
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+
+/// `perf_event_attr` up to `PERF_ATTR_SIZE_VER0`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+#[cfg(target_os = "linux")]
+const _ASSERT_ATTR_SIZE: () = [(); 1][std::mem::size_of::<PerfEventAttr>() - 64];
+
+struct PerfEvent(std::os::fd::OwnedFd);
+
+impl PerfEvent {
+    #[cfg(target_os = "linux")]
+    fn open(config: u64) -> Option<PerfEvent> {
+        use std::os::fd::FromRawFd;
+
+        const EXCLUDE_HV: u64 = 1 << 6;
+        const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+        let attr = PerfEventAttr {
+            kind: PERF_TYPE_SOFTWARE,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            flags: EXCLUDE_HV,
+            ..Default::default()
+        };
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0 as libc::pid_t,
+                -1 as libc::c_int,
+                -1 as libc::c_int,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+
+        if fd < 0 {
+            static WARN: std::sync::Once = std::sync::Once::new();
+
+            let err = std::io::Error::last_os_error();
+            WARN.call_once(|| eprintln!("perf_event_open failed: {:?}", err));
+            return None;
+        }
+
+        Some(PerfEvent(unsafe {
+            std::os::fd::OwnedFd::from_raw_fd(fd as _)
+        }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open(_config: u64) -> Option<PerfEvent> {
+        None
+    }
+
+    fn read(&self) -> Option<u64> {
+        use std::os::fd::AsRawFd;
+
+        let mut n = 0u64;
+        let ret = unsafe {
+            libc::read(
+                self.0.as_raw_fd(),
+                (&mut n as *mut u64).cast(),
+                std::mem::size_of::<u64>(),
+            )
+        };
+
+        (ret == std::mem::size_of::<u64>() as isize).then_some(n)
+    }
+}
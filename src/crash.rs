@@ -0,0 +1,72 @@
+//! Write out the buffered events when the process dies of a fatal signal.
+
+use crate::{events, output};
+use std::sync::OnceLock;
+use std::sync::atomic::{self, AtomicBool};
+
+const SIGNALS: [libc::c_int; 4] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGABRT, libc::SIGILL];
+
+/// The handlers replaced by ours, restored before the signal is re-raised
+static OLD_ACTIONS: [OnceLock<libc::sigaction>; SIGNALS.len()] =
+    [const { OnceLock::new() }; SIGNALS.len()];
+
+pub fn install() {
+    events::track_threads();
+
+    for (sig, old_action) in SIGNALS.iter().zip(&OLD_ACTIONS) {
+        unsafe {
+            let mut action = std::mem::zeroed::<libc::sigaction>();
+            action.sa_sigaction = handler as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut old = std::mem::zeroed::<libc::sigaction>();
+            if libc::sigaction(*sig, &action, &mut old) != 0 {
+                eprintln!(
+                    "install crash handler for signal {} failed: {:?}",
+                    sig,
+                    std::io::Error::last_os_error()
+                );
+                continue;
+            }
+            let _ = old_action.set(old);
+        }
+    }
+}
+
+extern "C" fn handler(sig: libc::c_int, info: *mut libc::siginfo_t, _ctx: *mut libc::c_void) {
+    static CRASHED: AtomicBool = AtomicBool::new(false);
+
+    let (code, addr) = unsafe { ((*info).si_code, (*info).si_addr() as u64) };
+
+    // only the first crashing thread writes, the others go straight to the old action
+    if !CRASHED.swap(true, atomic::Ordering::SeqCst) {
+        // sent by kill, raise or abort, there is no faulting address
+        let addr = if code <= 0 { 0 } else { addr };
+
+        // let the writer drain the chunks already sent, so the marker comes last
+        output::wait_flushed(500);
+        unsafe {
+            events::write_crash(sig, addr);
+        }
+    }
+
+    let old = SIGNALS
+        .iter()
+        .position(|&s| s == sig)
+        .and_then(|idx| OLD_ACTIONS[idx].get());
+    unsafe {
+        match old {
+            Some(old) => libc::sigaction(sig, old, std::ptr::null_mut()),
+            None => {
+                libc::signal(sig, libc::SIG_DFL);
+                0
+            }
+        };
+
+        // a fault happens again on return, a sent signal has to be raised again
+        if code <= 0 {
+            libc::raise(sig);
+        }
+    }
+}
@@ -1,38 +1,249 @@
 use crate::arch::{Args, ReturnValue};
-use crate::{FuncId, OUTPUT};
-use crate::{SETUP_THREAD, SETUP_THREAD_ONLY, layout::*};
-use std::cell::RefCell;
-use std::io::Write;
-use std::sync::LazyLock;
-use std::sync::atomic::{self, AtomicU32};
-use std::time::Instant;
+use crate::config::Clock;
+use crate::{FuncId, function_address};
+use crate::{SETUP_THREAD, SETUP_THREAD_ONLY, THREAD_FILTER, TRIGGER_ONLY, layout::*};
+use crate::{counter, hook, output, slow};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{self, AtomicBool, AtomicI64, AtomicPtr, AtomicU32};
+use std::sync::{LazyLock, mpsc};
+use std::time::{Duration, Instant};
+use std::{ptr, thread};
 
 struct Local {
     tid: Option<u32>,
-    buf: Vec<u8>,
+    /// index in `THREADS`, if registered
+    slot: Option<usize>,
+    /// the buffer of the thread until it registers, then it is in the slot
+    buffer: Buffer,
+    trigger_depth: u32,
+    /// set by a panic, `trigger_depth` is counted again from `stack` at the next exit
+    unwound: bool,
+    /// entry sled ids of the instrumented functions the thread is in,
+    /// with the entry time if the slow call watchdog is on and whether it is a trigger
+    stack: Vec<(u32, u64, bool)>,
+    /// a slow call found by the last exit, reported once the thread local is released
+    slow: Option<(u32, u64)>,
+    /// set by `sftrace_set_thread_enabled`
+    enabled: Option<bool>,
+    /// whether the thread name matches `THREAD_FILTER`, checked on the first event
+    name_match: Option<bool>,
     line: Vec<u8>,
+    counters: Option<counter::Counters>,
+    /// the CPU of the last `Kind::CPU` event
+    cpu: Option<u32>,
+}
+
+/// The events of a thread not yet handed over to the writer
+struct Buffer {
+    buf: Vec<u8>,
+    events: u32,
+    /// dropped events not yet reported to the writer
+    dropped: DroppedEvent,
+    spare: Option<Spare>,
+}
+
+/// Buffers given back by the writer thread
+type Spare = (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>);
+
+impl Buffer {
+    const fn new() -> Buffer {
+        Buffer {
+            buf: Vec::new(),
+            events: 0,
+            dropped: DroppedEvent {
+                limit: 0,
+                reentrant: 0,
+                teardown: 0,
+            },
+            spare: None,
+        }
+    }
+
+    #[cold]
+    fn reserve(&mut self, n: usize) {
+        self.buf.reserve(n);
+    }
+
+    /// Hand over the buffer from the thread that owns it.
+    fn flush(&mut self, tid: u32) {
+        if let Ok(reentrant) = REENTRANT.try_with(Cell::take) {
+            self.dropped.reentrant += reentrant;
+        }
+
+        hand_over(tid, self);
+    }
 }
 
 thread_local! {
     static LOCAL: RefCell<Local> = const {
         RefCell::new(Local {
             tid: None,
-            buf: Vec::new(),
-            line: Vec::new()
+            slot: None,
+            buffer: Buffer::new(),
+            trigger_depth: 0,
+            unwound: false,
+            stack: Vec::new(),
+            slow: None,
+            enabled: None,
+            name_match: None,
+            line: Vec::new(),
+            counters: None,
+            cpu: None,
         })
     };
+
+    /// Events dropped while `LOCAL` is borrowed, outside of it so it can be counted then
+    static REENTRANT: Cell<u64> = const { Cell::new(0) };
 }
 
 impl Drop for Local {
     fn drop(&mut self) {
+        if let Some(tid) = self.tid
+            && output::is_enabled()
+        {
+            if output::limit_reached() {
+                with_buffer(self.slot, &mut self.buffer, |buffer| {
+                    buffer.dropped.limit += 1;
+                });
+            } else {
+                self.thread_event(Kind::THREAD_END, tid, None);
+                with_buffer(self.slot, &mut self.buffer, |buffer| {
+                    buffer.buf.append(&mut self.line);
+                });
+            }
+        }
+
         self.flush();
+
+        if let Some(slot) = self.slot.take() {
+            let _guard = SlotGuard::lock(slot);
+            let registered = THREADS[slot]
+                .registered
+                .swap(ptr::null_mut(), atomic::Ordering::AcqRel);
+            drop(unsafe { Box::from_raw(registered) });
+        }
     }
 }
 
+const MAX_THREADS: usize = 4096;
+
+/// Buffers of the live threads, flushed by the flusher thread and the crash handler
+static THREADS: [Slot; MAX_THREADS] = [const {
+    Slot {
+        registered: AtomicPtr::new(ptr::null_mut()),
+        busy: AtomicBool::new(false),
+    }
+}; MAX_THREADS];
+
+/// Whether new threads register in `THREADS`
+static TRACK_THREADS: AtomicBool = AtomicBool::new(false);
+
+struct Slot {
+    /// owned by the slot, freed by the thread when it goes away
+    registered: AtomicPtr<Registered>,
+    /// held while the buffer is appended to or handed over
+    busy: AtomicBool,
+}
+
+/// The buffer of a registered thread, shared with the other threads through its slot
+struct Registered {
+    tid: u32,
+    buffer: Buffer,
+}
+
+struct SlotGuard(&'static AtomicBool);
+
+impl SlotGuard {
+    fn lock(slot: usize) -> SlotGuard {
+        let busy = &THREADS[slot].busy;
+        while busy
+            .compare_exchange_weak(
+                false,
+                true,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        SlotGuard(busy)
+    }
+
+    fn try_lock(slot: usize) -> Option<SlotGuard> {
+        let busy = &THREADS[slot].busy;
+        busy.compare_exchange(
+            false,
+            true,
+            atomic::Ordering::Acquire,
+            atomic::Ordering::Relaxed,
+        )
+        .ok()
+        .map(|_| SlotGuard(busy))
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.0.store(false, atomic::Ordering::Release);
+    }
+}
+
+/// Run `f` on the buffer of the thread, in its slot and under the lock if it is registered.
+fn with_buffer<R>(slot: Option<usize>, own: &mut Buffer, f: impl FnOnce(&mut Buffer) -> R) -> R {
+    let Some(slot) = slot else {
+        return f(own);
+    };
+
+    let _guard = SlotGuard::lock(slot);
+    // only the owning thread frees it, and the other threads wait for the lock
+    let registered = THREADS[slot].registered.load(atomic::Ordering::Acquire);
+    f(unsafe { &mut (*registered).buffer })
+}
+
+/// Register the threads that record from now on, so other threads can reach their buffers.
+pub fn track_threads() {
+    TRACK_THREADS.store(true, atomic::Ordering::Relaxed);
+}
+
 fn dur2u64(dur: std::time::Duration) -> u64 {
     dur.as_nanos() as u64
 }
 
+/// `clockid_t` of the timestamps, or -1 for relative to the first event
+static CLOCK: AtomicI64 = AtomicI64::new(-1);
+
+pub fn set_clock(clock: Clock) {
+    let id = match clock {
+        Clock::Relative => None,
+        Clock::Monotonic => Some(libc::CLOCK_MONOTONIC),
+        #[cfg(target_os = "linux")]
+        Clock::Boottime => Some(libc::CLOCK_BOOTTIME),
+        #[cfg(not(target_os = "linux"))]
+        Clock::Boottime => Some(libc::CLOCK_MONOTONIC),
+        Clock::Realtime => Some(libc::CLOCK_REALTIME),
+    };
+    CLOCK.store(id.map_or(-1, i64::from), atomic::Ordering::Relaxed);
+}
+
+pub fn now() -> u64 {
+    static NOW: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+    let clock = CLOCK.load(atomic::Ordering::Relaxed);
+    if clock < 0 {
+        return dur2u64(NOW.elapsed());
+    }
+
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(clock as libc::clockid_t, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
 impl Local {
     #[inline]
     pub fn record(
@@ -43,61 +254,287 @@ impl Local {
         return_value: Option<&ReturnValue>,
         alloc_event: Option<&AllocEvent>,
     ) {
-        if SETUP_THREAD_ONLY.load(atomic::Ordering::Relaxed) && !SETUP_THREAD.get() {
+        // Uninitialized, ignored
+        if !output::is_enabled() {
             return;
         }
 
-        static NOW: LazyLock<Instant> = LazyLock::new(Instant::now);
-
-        const CAP: usize = 4 * 1024;
+        if matches!(kind, Kind::ENTRY | Kind::EXIT | Kind::TAIL_CALL) {
+            let (func_id, flag) = FuncId(func_id).unpack();
+            self.shadow(kind, func_id, flag.contains(FuncFlag::TRIGGER));
+        }
 
-        // Uninitialized, ignored
-        if OUTPUT.get().is_none() {
+        if !self.is_enabled() {
             return;
         }
 
         let func_id = FuncId(func_id);
         let (func_id, flag) = func_id.unpack();
 
+        if TRIGGER_ONLY.load(atomic::Ordering::Relaxed) && !self.trigger(kind, flag) {
+            return;
+        }
+
+        if output::limit_reached() {
+            with_buffer(self.slot, &mut self.buffer, |buffer| {
+                buffer.dropped.limit += 1;
+            });
+            return;
+        }
+
+        let tid = match self.tid {
+            Some(tid) => tid,
+            None => self.init_thread(),
+        };
+
+        let (cpu_time, counters) = match kind {
+            Kind::ENTRY | Kind::EXIT | Kind::TAIL_CALL => {
+                if let Some(cpu) = counter::current_cpu()
+                    && self.cpu != Some(cpu)
+                {
+                    self.cpu = Some(cpu);
+                    self.migrate(tid, cpu);
+                }
+
+                let counters = self.counters.get_or_insert_with(|| {
+                    counter::Counters::open(counter::CounterFlag::current())
+                });
+                (counter::thread_cpu_time(), counters.read())
+            }
+            _ => (None, None),
+        };
+
         let event: Event<&Args, &ReturnValue, &AllocEvent> = Event {
             kind,
             func_id,
             alloc_event,
-            time: dur2u64(NOW.elapsed()),
-            tid: *self.tid.get_or_insert_with(|| {
-                // TODO use std::thread::Thread.id().as_u64()
-                static THREAD_ID: AtomicU32 = AtomicU32::new(0);
-
-                THREAD_ID.fetch_add(1, atomic::Ordering::Relaxed)
-            }),
+            time: now(),
+            tid,
             args: args.filter(|_| flag.contains(FuncFlag::LOG)),
             return_value: return_value.filter(|_| flag.contains(FuncFlag::LOG)),
+            cpu_time,
+            counters,
+            dropped: None,
+            crash: None,
+            thread: None,
+            cpu: None,
         };
         cbor4ii::serde::to_writer(&mut self.line, &event).unwrap();
 
-        if self.buf.capacity() == 0 {
-            self.reserve(CAP);
+        // the flusher may take the buffer between events, never in the middle of one
+        with_buffer(self.slot, &mut self.buffer, |buffer| {
+            let cap = output::buffer_size();
+
+            if buffer.buf.capacity() == 0 {
+                buffer.reserve(cap);
+            }
+
+            if !buffer.buf.is_empty() && buffer.buf.len() + self.line.len() > cap {
+                buffer.flush(tid);
+            }
+
+            buffer.buf.append(&mut self.line);
+            buffer.events += 1;
+        });
+    }
+
+    /// Keep the stack of the instrumented functions, whether the event is recorded or not.
+    fn shadow(&mut self, kind: Kind, func_id: u32, trigger: bool) {
+        if kind == Kind::ENTRY {
+            let start = if slow::is_enabled() { now() } else { 0 };
+            self.stack.push((func_id, start, trigger));
+            return;
+        }
+
+        // A tail call leaves the frame like an exit, the callee pushes its own.
+        // Unwinding skips exits, the next exit below closes those frames too.
+        let func = function_address(func_id);
+        if let Some(pos) = self
+            .stack
+            .iter()
+            .rposition(|&(id, ..)| function_address(id) == func)
+        {
+            let (entry_id, start, _) = self.stack[pos];
+            self.stack.truncate(pos);
+
+            if start != 0 {
+                let duration = now().saturating_sub(start);
+                if slow::is_slow(entry_id, duration) {
+                    self.slow = Some((entry_id, duration));
+                }
+            }
+        }
+    }
+
+    fn is_enabled(&mut self) -> bool {
+        if let Some(enabled) = self.enabled {
+            return enabled;
         }
 
-        if !self.buf.is_empty() && self.buf.len() + self.line.len() > CAP {
-            self.flush();
+        if SETUP_THREAD_ONLY.load(atomic::Ordering::Relaxed) && !SETUP_THREAD.get() {
+            return false;
         }
 
-        self.buf.append(&mut self.line);
+        match THREAD_FILTER.get() {
+            Some(filter) => *self.name_match.get_or_insert_with(|| {
+                crate::util::thread_name().is_some_and(|name| filter.is_match(&name))
+            }),
+            None => true,
+        }
+    }
+
+    /// Track the depth inside trigger functions, returns whether the event is inside,
+    /// the entry and exit of a trigger included.
+    fn trigger(&mut self, kind: Kind, flag: FuncFlag) -> bool {
+        if self.unwound && matches!(kind, Kind::EXIT | Kind::TAIL_CALL) {
+            // The shadow stack has closed the unwound frames by now, the panic may have
+            // been caught inside a trigger. Count the triggers left and the one exiting.
+            self.unwound = false;
+            let depth = self.stack.iter().filter(|&&(.., trigger)| trigger).count();
+            self.trigger_depth = depth as u32 + u32::from(flag.contains(FuncFlag::TRIGGER));
+        }
+
+        if !flag.contains(FuncFlag::TRIGGER) {
+            return self.trigger_depth > 0;
+        }
+
+        match kind {
+            Kind::ENTRY => {
+                self.trigger_depth += 1;
+                true
+            }
+            Kind::EXIT | Kind::TAIL_CALL => {
+                let inside = self.trigger_depth > 0;
+                self.trigger_depth = self.trigger_depth.saturating_sub(1);
+                inside
+            }
+            _ => self.trigger_depth > 0,
+        }
+    }
+
+    fn unwind_trigger(&mut self) {
+        // Unwinding skips the exits of the triggers, the depth is off until the next exit.
+        self.unwound = true;
     }
 
     #[cold]
-    fn reserve(&mut self, n: usize) {
-        self.buf.reserve(n);
+    fn init_thread(&mut self) -> u32 {
+        // TODO use std::thread::Thread.id().as_u64()
+        static THREAD_ID: AtomicU32 = AtomicU32::new(0);
+
+        let tid = THREAD_ID.fetch_add(1, atomic::Ordering::Relaxed);
+        self.tid = Some(tid);
+
+        if TRACK_THREADS.load(atomic::Ordering::Relaxed) {
+            let registered = Box::into_raw(Box::new(Registered {
+                tid,
+                buffer: std::mem::replace(&mut self.buffer, Buffer::new()),
+            }));
+            self.slot = THREADS.iter().position(|slot| {
+                slot.registered
+                    .compare_exchange(
+                        ptr::null_mut(),
+                        registered,
+                        atomic::Ordering::AcqRel,
+                        atomic::Ordering::Relaxed,
+                    )
+                    .is_ok()
+            });
+
+            // all slots taken, keep the buffer local
+            if self.slot.is_none() {
+                self.buffer = unsafe { Box::from_raw(registered) }.buffer;
+            }
+        }
+
+        self.thread_event(Kind::THREAD_START, tid, crate::thread::parent());
+
+        tid
+    }
+
+    /// Serialize a thread start or end into `line`.
+    fn thread_event(&mut self, kind: Kind, tid: u32, parent: Option<(u32, u64)>) {
+        let event: Event<(), (), ()> = Event {
+            tid,
+            func_id: 0,
+            time: now(),
+            kind,
+            args: None,
+            return_value: None,
+            alloc_event: None,
+            cpu_time: None,
+            counters: None,
+            dropped: None,
+            crash: None,
+            thread: Some(ThreadEvent {
+                os_tid: crate::util::os_tid(),
+                parent: parent.map(|(tid, _)| tid),
+                spawn_time: parent.map(|(_, time)| time),
+            }),
+            cpu: None,
+        };
+        cbor4ii::serde::to_writer(&mut self.line, &event).unwrap();
+    }
+
+    /// Serialize a `Kind::CPU` event into `line`.
+    #[cold]
+    fn migrate(&mut self, tid: u32, cpu: u32) {
+        let event: Event<(), (), ()> = Event {
+            tid,
+            func_id: 0,
+            time: now(),
+            kind: Kind::CPU,
+            args: None,
+            return_value: None,
+            alloc_event: None,
+            cpu_time: None,
+            counters: None,
+            dropped: None,
+            crash: None,
+            thread: None,
+            cpu: Some(cpu),
+        };
+        cbor4ii::serde::to_writer(&mut self.line, &event).unwrap();
     }
 
     pub fn flush(&mut self) {
-        if let Some(mut output) = OUTPUT.get() {
-            // We assume that writes are atomic (<= 4k)
-            output.write_all(&self.buf).unwrap();
-            self.buf.clear();
-        }
+        let Some(tid) = self.tid else {
+            return;
+        };
+
+        with_buffer(self.slot, &mut self.buffer, |buffer| buffer.flush(tid));
+    }
+}
+
+/// Send the buffer to the writer, the caller holds the slot of the thread if it has one.
+fn hand_over(tid: u32, buffer: &mut Buffer) {
+    if buffer.buf.is_empty() && buffer.dropped.total() == 0 {
+        return;
     }
+
+    // Swap in the buffer given back by the writer,
+    // allocate a new one only if it is still in flight.
+    let (back, spare) = buffer.spare.get_or_insert_with(mpsc::channel);
+    let next = spare
+        .try_recv()
+        .unwrap_or_else(|_| Vec::with_capacity(output::buffer_size()));
+    let buf = std::mem::replace(&mut buffer.buf, next);
+
+    output::send(output::Chunk {
+        tid,
+        buf,
+        events: std::mem::take(&mut buffer.events),
+        dropped: std::mem::take(&mut buffer.dropped),
+        back: back.clone(),
+    });
+}
+
+/// The sftrace id of the current thread, if it has recorded anything
+pub fn current_tid() -> Option<u32> {
+    LOCAL
+        .try_with(|local| local.try_borrow().ok().and_then(|local| local.tid))
+        .ok()
+        .flatten()
 }
 
 pub fn flush_current_thread() {
@@ -108,48 +545,225 @@ pub fn flush_current_thread() {
     });
 }
 
-pub extern "C" fn record_entry(func_id: u32, args: &Args) {
+/// Hand over the buffers of all threads every `interval`,
+/// so threads that record rarely don't hold their events back.
+pub fn spawn_flusher(interval: Duration) {
+    track_threads();
+
+    thread::Builder::new()
+        .name("sftrace-flusher".into())
+        .spawn(move || {
+            // Handing over allocates while holding the slot of another thread,
+            // the allocator hook must not make the flusher a recording thread.
+            set_thread_enabled(false);
+
+            loop {
+                thread::sleep(interval);
+                flush_threads(false);
+            }
+        })
+        .expect("spawn flusher thread failed");
+}
+
+/// Hand over the buffers of the registered threads other than the current one,
+/// skip the ones busy recording unless `wait`.
+pub fn flush_threads(wait: bool) {
+    // holding our own slot while allocating would deadlock in the allocator hook
+    let current = LOCAL
+        .try_with(|local| local.try_borrow().ok().and_then(|local| local.slot))
+        .ok()
+        .flatten();
+
+    for (idx, slot) in THREADS.iter().enumerate() {
+        if Some(idx) == current || slot.registered.load(atomic::Ordering::Relaxed).is_null() {
+            continue;
+        }
+
+        // busy recording, it will flush on its own
+        let _guard = if wait {
+            SlotGuard::lock(idx)
+        } else {
+            match SlotGuard::try_lock(idx) {
+                Some(guard) => guard,
+                None => continue,
+            }
+        };
+
+        // freed before the thread local goes away, check again under the lock
+        let registered = slot.registered.load(atomic::Ordering::Acquire);
+        if registered.is_null() {
+            continue;
+        }
+
+        let registered = unsafe { &mut *registered };
+        hand_over(registered.tid, &mut registered.buffer);
+    }
+}
+
+/// A frame of the shadow stack, as returned by `sftrace_current_stack`
+#[repr(C)]
+pub struct Frame {
+    /// the entry sled id, as in the trace
+    pub func_id: u32,
+    /// runtime address of the function
+    pub addr: usize,
+}
+
+/// Write the shadow stack of the current thread into `frames`, returns its depth.
+///
+/// # Safety
+///
+/// `frames` must be valid for `cap` writes.
+pub unsafe fn current_stack(frames: *mut Frame, cap: usize) -> usize {
+    LOCAL
+        .try_with(|local| {
+            let Ok(local) = local.try_borrow() else {
+                return 0;
+            };
+
+            for (idx, &(func_id, ..)) in local.stack.iter().take(cap).enumerate() {
+                let frame = Frame {
+                    func_id,
+                    addr: function_address(func_id),
+                };
+                unsafe {
+                    frames.add(idx).write(frame);
+                }
+            }
+
+            local.stack.len()
+        })
+        .unwrap_or(0)
+}
+
+pub fn set_thread_enabled(enabled: bool) {
     let _ = LOCAL.try_with(|local| {
         if let Ok(mut local) = local.try_borrow_mut() {
-            local.record(Kind::ENTRY, func_id, Some(args), None, None);
+            local.enabled = Some(enabled);
         }
     });
 }
 
-pub extern "C" fn record_exit(func_id: u32, return_value: &ReturnValue) {
-    let _ = LOCAL.try_with(|local| {
-        if let Ok(mut local) = local.try_borrow_mut() {
-            local.record(Kind::EXIT, func_id, None, Some(return_value), None);
+/// Run `f` on the thread local state, or count the event as dropped if it is unavailable.
+#[inline]
+fn with_local(f: impl FnOnce(&mut Local)) {
+    let result = LOCAL.try_with(|local| match local.try_borrow_mut() {
+        Ok(mut local) => f(&mut local),
+        Err(_) if output::is_enabled() => {
+            let _ = REENTRANT.try_with(|n| n.set(n.get() + 1));
         }
+        Err(_) => (),
     });
+
+    if result.is_err() && output::is_enabled() {
+        output::count_teardown();
+    }
+}
+
+pub extern "C" fn record_entry(func_id: u32, args: &Args) {
+    with_local(|local| local.record(Kind::ENTRY, func_id, Some(args), None, None));
+    hook::entry(func_id, args);
+}
+
+pub extern "C" fn record_exit(func_id: u32, return_value: &ReturnValue) {
+    hook::exit(func_id, return_value);
+    record_leave(Kind::EXIT, func_id, Some(return_value));
 }
 
 pub extern "C" fn record_tailcall(func_id: u32) {
-    let _ = LOCAL.try_with(|local| {
-        if let Ok(mut local) = local.try_borrow_mut() {
-            local.record(Kind::TAIL_CALL, func_id, None, None, None);
-        }
+    record_leave(Kind::TAIL_CALL, func_id, None);
+}
+
+#[inline]
+fn record_leave(kind: Kind, func_id: u32, return_value: Option<&ReturnValue>) {
+    let mut slow = None;
+    with_local(|local| {
+        local.record(kind, func_id, None, return_value, None);
+        slow = local
+            .slow
+            .take()
+            .map(|(func_id, duration)| (func_id, duration, local.tid));
+    });
+
+    if let Some((func_id, duration, tid)) = slow {
+        slow::report(func_id, duration, tid);
+    }
+}
+
+pub fn record_unwind() {
+    with_local(|local| {
+        local.record(Kind::UNWIND, 0, None, None, None);
+        local.unwind_trigger();
     });
 }
 
 pub fn record_alloc(kind: u8, size: usize, align: usize, ptr: *mut u8) {
-    let _ = LOCAL.try_with(|local| {
-        if let Ok(mut local) = local.try_borrow_mut() {
-            let kind = match kind {
-                1 => Kind::ALLOC,
-                2 => Kind::DEALLOC,
-                3 => Kind::REALLOC_ALLOC,
-                4 => Kind::REALLOC_DEALLOC,
-                _ => panic!(),
-            };
+    with_local(|local| {
+        let kind = match kind {
+            1 => Kind::ALLOC,
+            2 => Kind::DEALLOC,
+            3 => Kind::REALLOC_ALLOC,
+            4 => Kind::REALLOC_DEALLOC,
+            _ => panic!(),
+        };
 
-            let event = AllocEvent {
-                size: size as u64,
-                align: align as u64,
-                ptr: ptr as usize as u64,
-            };
+        let event = AllocEvent {
+            size: size as u64,
+            align: align as u64,
+            ptr: ptr as usize as u64,
+        };
 
-            local.record(kind, 0, None, None, Some(&event));
-        }
+        local.record(kind, 0, None, None, Some(&event));
     });
 }
+
+/// Write out the buffers of all threads and a crash marker, called from the crash handler.
+///
+/// # Safety
+///
+/// Must only be used when the process is about to die.
+pub unsafe fn write_crash(signal: i32, addr: u64) {
+    for (idx, slot) in THREADS.iter().enumerate() {
+        // appending or handing over, possibly the crashing thread itself, skip it
+        let Some(_guard) = SlotGuard::try_lock(idx) else {
+            continue;
+        };
+
+        let registered = slot.registered.load(atomic::Ordering::Acquire);
+        if registered.is_null() {
+            continue;
+        }
+
+        let (tid, buf) = unsafe { ((*registered).tid, &(*registered).buffer.buf) };
+        output::write_raw(tid, buf);
+    }
+
+    let tid = LOCAL
+        .try_with(|local| unsafe { (*local.as_ptr()).tid })
+        .ok()
+        .flatten()
+        .unwrap_or(WRITER_TID);
+    let event: Event<(), (), ()> = Event {
+        tid,
+        func_id: 0,
+        time: now(),
+        kind: Kind::CRASH,
+        args: None,
+        return_value: None,
+        alloc_event: None,
+        cpu_time: None,
+        counters: None,
+        dropped: None,
+        crash: Some(CrashEvent { signal, addr }),
+        thread: None,
+        cpu: None,
+    };
+
+    // serialize into the stack, allocating is not allowed here
+    let mut buf = [0u8; 128];
+    let mut writer = &mut buf[..];
+    if cbor4ii::serde::to_writer(&mut writer, &event).is_ok() {
+        let len = 128 - writer.len();
+        output::write_raw(tid, &buf[..len]);
+    }
+}
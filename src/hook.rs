@@ -0,0 +1,78 @@
+//! User hooks run on the entry and exit of chosen functions.
+
+use crate::arch::{Args, ReturnValue};
+use crate::{FUNCTIONS, FuncId};
+use std::sync::OnceLock;
+use std::sync::atomic::{self, AtomicUsize};
+
+pub type EntryHook = extern "C" fn(func_id: u32, args: &Args);
+pub type ExitHook = extern "C" fn(func_id: u32, return_value: &ReturnValue);
+
+/// Hook addresses by sled index, 0 is none
+struct Hooks {
+    entry: Box<[AtomicUsize]>,
+    exit: Box<[AtomicUsize]>,
+}
+
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+
+pub fn set_entry(addr: usize, hook: Option<EntryHook>) -> usize {
+    set(addr, hook.map_or(0, |hook| hook as usize), |hooks| {
+        &hooks.entry
+    })
+}
+
+pub fn set_exit(addr: usize, hook: Option<ExitHook>) -> usize {
+    set(addr, hook.map_or(0, |hook| hook as usize), |hooks| {
+        &hooks.exit
+    })
+}
+
+/// Set the hook on the sleds of the function at `addr`, returns how many there are.
+fn set(addr: usize, hook: usize, table: fn(&Hooks) -> &[AtomicUsize]) -> usize {
+    let Some(functions) = FUNCTIONS.get() else {
+        return 0;
+    };
+
+    let hooks = HOOKS.get_or_init(|| Hooks {
+        entry: functions.iter().map(|_| AtomicUsize::new(0)).collect(),
+        exit: functions.iter().map(|_| AtomicUsize::new(0)).collect(),
+    });
+    let table = table(hooks);
+
+    let mut n = 0;
+    for (idx, &function) in functions.iter().enumerate() {
+        if function == addr {
+            table[idx].store(hook, atomic::Ordering::Release);
+            n += 1;
+        }
+    }
+    n
+}
+
+#[inline]
+fn get(func_id: u32, table: fn(&Hooks) -> &[AtomicUsize]) -> Option<(u32, usize)> {
+    let hooks = HOOKS.get()?;
+    let (func_id, _) = FuncId(func_id).unpack();
+    let hook = table(hooks)
+        .get(func_id as usize)?
+        .load(atomic::Ordering::Acquire);
+
+    (hook != 0).then_some((func_id, hook))
+}
+
+#[inline]
+pub fn entry(func_id: u32, args: &Args) {
+    if let Some((func_id, hook)) = get(func_id, |hooks| &hooks.entry) {
+        let hook: EntryHook = unsafe { std::mem::transmute(hook) };
+        hook(func_id, args);
+    }
+}
+
+#[inline]
+pub fn exit(func_id: u32, return_value: &ReturnValue) {
+    if let Some((func_id, hook)) = get(func_id, |hooks| &hooks.exit) {
+        let hook: ExitHook = unsafe { std::mem::transmute(hook) };
+        hook(func_id, return_value);
+    }
+}
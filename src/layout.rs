@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use zerocopy::byteorder::{NativeEndian, U64};
+use zerocopy::byteorder::{LittleEndian, NativeEndian, U32, U64};
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 pub const SIGN_TRACE: &[u8; 8] = b"sf\0trace";
@@ -15,6 +15,15 @@ pub struct Metadata {
     pub shlib_path: PathBuf,
 }
 
+/// Every chunk of events is prefixed with this header,
+/// followed by `len` bytes of cbor events from thread `tid`.
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ChunkHeader {
+    pub tid: U32<LittleEndian>,
+    pub len: U32<LittleEndian>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Event<ARGS, RV, ALLOC> {
     #[serde(rename = "t")]
@@ -36,6 +45,54 @@ pub struct Event<ARGS, RV, ALLOC> {
     #[serde(rename = "A")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub alloc_event: Option<ALLOC>,
+    #[serde(rename = "c")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_time: Option<u64>,
+    #[serde(rename = "C")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counters: Option<Counters>,
+    #[serde(rename = "d")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<DroppedEvent>,
+    #[serde(rename = "x")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash: Option<CrashEvent>,
+    #[serde(rename = "h")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<ThreadEvent>,
+    /// the CPU the thread runs on from now on, see `Kind::CPU`
+    #[serde(rename = "u")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+}
+
+/// Events written by the writer thread itself use this tid.
+#[allow(dead_code)]
+pub const WRITER_TID: u32 = u32::MAX;
+
+/// Set by `sftrace doctor`, the recorder then prints a `Diagnostic` per line
+/// after `DIAGNOSTIC_PREFIX`, besides its usual messages.
+pub const DIAGNOSTIC_ENV: &str = "SFTRACE_DIAGNOSTIC";
+pub const DIAGNOSTIC_PREFIX: &str = "sftrace-diagnostic: ";
+
+/// Setup problems and results, in a form that does not depend on the message wording
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Diagnostic {
+    /// the text segment could not be made writable
+    UnlockFailed { error: String },
+    /// the object on disk is not the one loaded
+    BuildIdMismatch,
+    /// sleds skipped because they do not match the expected pattern
+    InvalidSleds { count: usize },
+    /// triggers are configured but none of them is patched
+    NoTrigger,
+    /// sleds patched per kind
+    Patched {
+        entry: usize,
+        exit: usize,
+        tail_call: usize,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -48,6 +105,77 @@ pub struct AllocEvent {
     pub ptr: u64,
 }
 
+/// Number of events that were not written, by reason
+///
+/// Written once per thread at shutdown, the ones that can't be told apart
+/// by thread use `WRITER_TID`.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+pub struct DroppedEvent {
+    /// because the output size limit was reached
+    #[serde(rename = "l")]
+    pub limit: u64,
+    /// because the recorder was already running on the thread,
+    /// instrumented code called by the recorder itself or a signal handler
+    #[serde(rename = "r")]
+    #[serde(skip_serializing_if = "u64_is_zero")]
+    #[serde(default)]
+    pub reentrant: u64,
+    /// because the thread local buffer was already destroyed
+    #[serde(rename = "d")]
+    #[serde(skip_serializing_if = "u64_is_zero")]
+    #[serde(default)]
+    pub teardown: u64,
+}
+
+impl DroppedEvent {
+    pub fn total(&self) -> u64 {
+        self.limit + self.reentrant + self.teardown
+    }
+
+    #[allow(dead_code)]
+    pub fn add(&mut self, other: &DroppedEvent) {
+        self.limit += other.limit;
+        self.reentrant += other.reentrant;
+        self.teardown += other.teardown;
+    }
+}
+
+/// The process was killed by a fatal signal
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct CrashEvent {
+    #[serde(rename = "s")]
+    pub signal: i32,
+    /// faulting address, 0 if the signal was not a fault
+    #[serde(rename = "a")]
+    pub addr: u64,
+}
+
+/// A thread started recording or exited
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ThreadEvent {
+    #[serde(rename = "o")]
+    pub os_tid: u64,
+    /// the thread that spawned it and when, if it was recording then
+    #[serde(rename = "p")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub parent: Option<u32>,
+    #[serde(rename = "s")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub spawn_time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Counters {
+    #[serde(rename = "x")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ctx_switches: Option<u64>,
+    #[serde(rename = "p")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_faults: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
 #[serde(transparent)]
 pub struct Kind(u8);
@@ -60,6 +188,13 @@ impl Kind {
     pub const DEALLOC: Kind = Kind(5);
     pub const REALLOC_ALLOC: Kind = Kind(6);
     pub const REALLOC_DEALLOC: Kind = Kind(7);
+    pub const UNWIND: Kind = Kind(8);
+    pub const DROPPED: Kind = Kind(9);
+    pub const CRASH: Kind = Kind(10);
+    pub const THREAD_START: Kind = Kind(11);
+    pub const THREAD_END: Kind = Kind(12);
+    /// The thread is on another CPU, written before the first entry or exit there
+    pub const CPU: Kind = Kind(13);
 
     #[allow(dead_code)]
     pub fn as_u8(self) -> u8 {
@@ -71,6 +206,10 @@ fn u32_is_zero(n: &u32) -> bool {
     *n == 0
 }
 
+fn u64_is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
 pub fn build_id_hash(build_id: &[u8]) -> u64 {
     use siphasher::sip::SipHasher24;
 
@@ -91,36 +230,106 @@ pub struct XRayFunctionEntry {
 
 const _ASSERT_SIZE: () = [(); 1][std::mem::size_of::<XRayFunctionEntry>() - 32];
 
-pub struct XRayInstrMap<'a>(pub &'a [XRayFunctionEntry]);
+pub struct XRayInstrMap<'a> {
+    entries: &'a [XRayFunctionEntry],
+    relocs: Relocations,
+}
 
 pub struct XRayEntry<'a> {
     idx: u32,
     section_offset: u64,
     entry: &'a XRayFunctionEntry,
+    relocs: &'a Relocations,
 }
 
-impl XRayInstrMap<'_> {
+impl<'a> XRayInstrMap<'a> {
+    const ENTRY_SIZE: u64 = std::mem::size_of::<XRayFunctionEntry>() as u64;
+
+    pub fn new(entries: &'a [XRayFunctionEntry]) -> XRayInstrMap<'a> {
+        XRayInstrMap {
+            entries,
+            relocs: Relocations::default(),
+        }
+    }
+
+    /// Version 0/1 maps use absolute addresses,
+    /// which are filled in by dynamic relocations in a PIE or shared object.
+    pub fn relocate<'data>(
+        mut self,
+        obj: &impl object::Object<'data>,
+        section_offset: u64,
+    ) -> Self {
+        if self.entries.iter().any(|entry| entry.version < 2) {
+            let len = self.entries.len() as u64 * Self::ENTRY_SIZE;
+            self.relocs = Relocations::parse(obj, section_offset..section_offset + len);
+        }
+
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
     #[allow(dead_code)]
     pub fn get(&self, section_offset: u64, idx: u32) -> XRayEntry<'_> {
         let idx2: usize = idx.try_into().unwrap();
         XRayEntry {
             idx,
             section_offset,
-            entry: &self.0[idx2],
+            entry: &self.entries[idx2],
+            relocs: &self.relocs,
         }
     }
 
     pub fn iter(&self, section_offset: u64) -> impl Iterator<Item = XRayEntry<'_>> + '_ {
-        self.0
+        self.entries
             .iter()
             .enumerate()
-            .filter(|(_, entry)| entry.version == 2)
+            .filter(|(_, entry)| entry.version <= 2)
             .map(move |(idx, entry)| XRayEntry {
                 idx: idx.try_into().unwrap(),
                 section_offset,
                 entry,
+                relocs: &self.relocs,
             })
     }
+
+    /// Sleds grouped per function, by `xray_fn_idx` if available,
+    /// otherwise by consecutive sleds of the same function.
+    pub fn functions(
+        &self,
+        section_offset: u64,
+        index: Option<&XRayFunctionIndex>,
+    ) -> Vec<XRayFunction> {
+        if let Some(index) = index {
+            return index
+                .0
+                .iter()
+                .filter(|sleds| !sleds.is_empty())
+                .map(|sleds| XRayFunction {
+                    address: self.get(section_offset, sleds.start).function(),
+                    sleds: sleds.clone(),
+                })
+                .collect();
+        }
+
+        let mut functions: Vec<XRayFunction> = Vec::new();
+        for entry in self.iter(section_offset) {
+            let address = entry.function();
+
+            match functions.last_mut() {
+                Some(func) if func.address == address && func.sleds.end == entry.id() => {
+                    func.sleds.end += 1;
+                }
+                _ => functions.push(XRayFunction {
+                    address,
+                    sleds: entry.id()..entry.id() + 1,
+                }),
+            }
+        }
+        functions
+    }
 }
 
 impl XRayEntry<'_> {
@@ -135,17 +344,134 @@ impl XRayEntry<'_> {
         self.entry.kind
     }
 
+    #[allow(dead_code)]
+    pub fn always_instrument(&self) -> bool {
+        self.entry.always_instrument != 0
+    }
+
     #[allow(dead_code)]
     pub fn address(&self) -> u64 {
         // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/compiler-rt/lib/xray/xray_interface_internal.h#L59
         // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/llvm/lib/XRay/InstrumentationMap.cpp#L199
         let entry_offset = self.section_offset + u64::from(self.idx) * Self::ENTRY_SIZE;
-        entry_offset + self.entry.address.get()
+        match self.entry.version {
+            2 => entry_offset + self.entry.address.get(),
+            _ => self
+                .relocs
+                .get(entry_offset)
+                .unwrap_or(self.entry.address.get()),
+        }
     }
 
     pub fn function(&self) -> u64 {
         let entry_offset = self.section_offset + u64::from(self.idx) * Self::ENTRY_SIZE;
-        entry_offset + self.entry.function.get() + std::mem::size_of::<u64>() as u64
+        let field_offset = entry_offset + std::mem::size_of::<u64>() as u64;
+        match self.entry.version {
+            2 => field_offset + self.entry.function.get(),
+            _ => self
+                .relocs
+                .get(field_offset)
+                .unwrap_or(self.entry.function.get()),
+        }
+    }
+}
+
+pub struct XRayFunction {
+    /// function address, as in `XRayEntry::function`
+    pub address: u64,
+    pub sleds: std::ops::Range<u32>,
+}
+
+/// Sled ranges per function from the `xray_fn_idx` section.
+pub struct XRayFunctionIndex(Vec<std::ops::Range<u32>>);
+
+impl XRayFunctionIndex {
+    /// Newer toolchains emit `{ pc-relative begin, count }` entries, older ones
+    /// `{ absolute begin, absolute end }`. The format is not versioned,
+    /// so use the one under which every entry points into `xray_instr_map`.
+    pub fn parse<'data>(
+        obj: &impl object::Object<'data>,
+        buf: &[u8],
+        section_offset: u64,
+        map: &XRayInstrMap<'_>,
+        map_offset: u64,
+    ) -> Option<XRayFunctionIndex> {
+        let words = <[[U64<NativeEndian>; 2]]>::ref_from_bytes(buf).ok()?;
+        let entry_size = XRayInstrMap::ENTRY_SIZE;
+        let map_end = map_offset + map.len() as u64 * entry_size;
+
+        let to_idx = |addr: u64| -> Option<u32> {
+            if !(map_offset..=map_end).contains(&addr)
+                || !(addr - map_offset).is_multiple_of(entry_size)
+            {
+                return None;
+            }
+            ((addr - map_offset) / entry_size).try_into().ok()
+        };
+
+        let pcrel = words
+            .iter()
+            .enumerate()
+            .map(|(i, [begin, count])| {
+                let entry_offset = section_offset + i as u64 * 16;
+                let begin = to_idx(entry_offset.wrapping_add(begin.get()))?;
+                let end = begin.checked_add(count.get().try_into().ok()?)?;
+                (end as usize <= map.len()).then_some(begin..end)
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(ranges) = pcrel {
+            return Some(XRayFunctionIndex(ranges));
+        }
+
+        let len = words.len() as u64 * 16;
+        let relocs = Relocations::parse(obj, section_offset..section_offset + len);
+        let absolute = words
+            .iter()
+            .enumerate()
+            .map(|(i, [begin, end])| {
+                let entry_offset = section_offset + i as u64 * 16;
+                let begin = relocs.get(entry_offset).unwrap_or(begin.get());
+                let end = relocs.get(entry_offset + 8).unwrap_or(end.get());
+                let (begin, end) = (to_idx(begin)?, to_idx(end)?);
+                (begin <= end).then_some(begin..end)
+            })
+            .collect::<Option<Vec<_>>>();
+
+        absolute.map(XRayFunctionIndex)
+    }
+}
+
+/// Values of the relative dynamic relocations, by address
+#[derive(Default)]
+pub struct Relocations(std::collections::HashMap<u64, u64>);
+
+impl Relocations {
+    pub fn parse<'data>(
+        obj: &impl object::Object<'data>,
+        range: std::ops::Range<u64>,
+    ) -> Relocations {
+        use object::{Architecture, RelocationFlags, elf};
+
+        let r_relative = match obj.architecture() {
+            Architecture::X86_64 => elf::R_X86_64_RELATIVE,
+            Architecture::Aarch64 => elf::R_AARCH64_RELATIVE,
+            _ => return Relocations::default(),
+        };
+
+        let map = obj
+            .dynamic_relocations()
+            .into_iter()
+            .flatten()
+            .filter(|(offset, _)| range.contains(offset))
+            .filter(|(_, reloc)| matches!(reloc.flags(), RelocationFlags::Elf { r_type } if r_type == r_relative))
+            .map(|(offset, reloc)| (offset, reloc.addend() as u64))
+            .collect();
+
+        Relocations(map)
+    }
+
+    fn get(&self, addr: u64) -> Option<u64> {
+        self.0.get(&addr).copied()
     }
 }
 
@@ -231,6 +557,14 @@ impl FilterMap {
         self.mode
     }
 
+    pub fn marks(&self) -> &[FilterMark] {
+        &self.map
+    }
+
+    pub fn has_flag(&self, flag: FuncFlag) -> bool {
+        self.map.iter().any(|mark| mark.flag().contains(flag))
+    }
+
     pub fn check(&self, addr: u64) -> Option<FilterMark> {
         self.map
             .binary_search_by_key(&addr, |mark| mark.addr())
@@ -262,5 +596,7 @@ bitflags::bitflags! {
     #[derive(Clone, Copy)]
     pub struct FuncFlag: u8 {
         const LOG   = 0b00000001;
+        /// only record while inside this function
+        const TRIGGER = 0b00000010;
     }
 }
@@ -1,41 +1,296 @@
 #![allow(clippy::uninlined_format_args)]
 
 mod arch;
+mod config;
+mod counter;
+mod crash;
 mod events;
+mod hook;
 mod layout;
+mod output;
+mod slow;
+mod thread;
 mod util;
 
-use object::{Object, ObjectSection};
-use std::io::Write;
-use std::sync::OnceLock;
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::collections::HashSet;
+use std::ffi::{CStr, c_char, c_int};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Mutex, Once, OnceLock};
 use std::{cell::Cell, fs};
 use util::{MProtect, page_size};
 
 static SETUP_THREAD_ONLY: AtomicBool = AtomicBool::new(false);
+/// Some functions are triggers, record only inside them
+static TRIGGER_ONLY: AtomicBool = AtomicBool::new(false);
+/// Only record the threads whose name matches
+static THREAD_FILTER: OnceLock<regex::Regex> = OnceLock::new();
+/// Runtime function address of every sled, by sled index
+static FUNCTIONS: OnceLock<Box<[usize]>> = OnceLock::new();
+/// Path and load address of the patched object, to look up symbols later
+static OBJECT: OnceLock<(PathBuf, usize)> = OnceLock::new();
+
+static INIT: Once = Once::new();
+
+/// Sleds patched so far, restored by `sftrace_detach`
+static PATCHED: Mutex<Vec<Patched>> = Mutex::new(Vec::new());
 
 thread_local! {
     static SETUP_THREAD: Cell<bool> = const { Cell::new(false) };
 }
 
+/// Patch the object that contains the slots, or the main executable if they are null,
+/// the way C and C++ programs call it, see `sftrace.h`.
 #[unsafe(no_mangle)]
 pub extern "C" fn sftrace_setup(
-    entry_slot: unsafe extern "C" fn(),
-    exit_slot: unsafe extern "C" fn(),
-    tailcall_slot: unsafe extern "C" fn(),
+    entry_slot: Option<unsafe extern "C" fn()>,
+    exit_slot: Option<unsafe extern "C" fn()>,
+    tailcall_slot: Option<unsafe extern "C" fn()>,
 ) {
-    use std::sync::Once;
+    let target = match (entry_slot, exit_slot, tailcall_slot) {
+        (Some(entry), Some(exit), Some(tailcall)) => Target::Setup(Slots {
+            entry: entry as usize,
+            exit: exit as usize,
+            tailcall: tailcall as usize,
+        }),
+        _ => Target::Main,
+    };
+
+    INIT.call_once(|| {
+        if let Some(config) = load_config() {
+            init(&config, target);
+        }
+    });
+
+    SETUP_THREAD.set(true);
+}
+
+#[cfg(target_os = "linux")]
+#[used]
+#[unsafe(link_section = ".init_array")]
+static PRELOAD: extern "C" fn() = preload;
+
+/// Loaded by `LD_PRELOAD`, patch the main executable before `main`.
+#[cfg(target_os = "linux")]
+extern "C" fn preload() {
+    if std::env::var_os("SFTRACE_PRELOAD").is_none_or(|key| key.is_empty()) {
+        return;
+    }
 
-    static ONCE: Once = Once::new();
+    INIT.call_once(|| {
+        if let Some(config) = load_config() {
+            init(&config, Target::Main);
+        }
+    });
 
-    ONCE.call_once(|| init(entry_slot, exit_slot, tailcall_slot));
+    SETUP_THREAD.set(true);
+}
 
-    if let Ok(key) = std::env::var("SFTRACE_SETUP_THREAD_ONLY")
-        && !key.is_empty()
-    {
-        SETUP_THREAD_ONLY.store(true, atomic::Ordering::Relaxed);
+/// Called by `sftrace attach` in the traced process after loading this library,
+/// patch the main executable and record to `output`. `config` can be null.
+///
+/// Returns 0 on success.
+///
+/// # Safety
+///
+/// `output` and `config` must be nul-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sftrace_attach(output: *const c_char, config: *const c_char) -> c_int {
+    if INIT.is_completed() {
+        eprintln!("sftrace: already initialized");
+        return -1;
     }
+
+    let output = unsafe { CStr::from_ptr(output) };
+    let config = (!config.is_null()).then(|| unsafe { CStr::from_ptr(config) });
+
+    let config = match config {
+        Some(path) => config::Config::load(Path::new(&*path.to_string_lossy())),
+        None => Ok(config::Config::default()),
+    };
+    let mut config = match config.and_then(config::Config::with_env) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{:?}", err);
+            return -1;
+        }
+    };
+    config.output.file = Some(output.to_string_lossy().into_owned().into());
+
+    INIT.call_once(|| init(&config, Target::Main));
+
     SETUP_THREAD.set(true);
+
+    if output::is_enabled() { 0 } else { -1 }
+}
+
+/// Called by `sftrace attach` when the duration is over,
+/// restore the patched sleds and write out the recorded events.
+#[unsafe(no_mangle)]
+pub extern "C" fn sftrace_detach() {
+    for patched in PATCHED.lock().unwrap().drain(..) {
+        patched.restore();
+    }
+
+    // idle threads may not record again, take their buffers from the registry
+    events::flush_threads(true);
+
+    shutdown();
+}
+
+/// Where the sleds jump to
+#[derive(Clone, Copy)]
+struct Slots {
+    entry: usize,
+    exit: usize,
+    tailcall: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    /// The object that contains the slots passed to `sftrace_setup`
+    Setup(Slots),
+    /// The main executable, loaded by `LD_PRELOAD`, `sftrace attach` or set up without slots
+    Main,
+}
+
+impl Slots {
+    fn trampolines() -> Slots {
+        Slots {
+            entry: arch::xray_entry as *const () as usize,
+            exit: arch::xray_exit as *const () as usize,
+            tailcall: arch::xray_tailcall as *const () as usize,
+        }
+    }
+
+    /// There are no slots in the executable in preload mode, jump straight to the
+    /// trampolines if they are in `rel32` reach, otherwise through slots mapped near the text.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn preload(text: Range<usize>) -> Option<Slots> {
+        let trampolines = Slots::trampolines();
+
+        let reach = |addr: usize| {
+            let start = text.start.min(addr);
+            let end = text.end.max(addr);
+            end - start < i32::MAX as usize
+        };
+        if [trampolines.entry, trampolines.exit, trampolines.tailcall]
+            .into_iter()
+            .all(reach)
+        {
+            return Some(trampolines);
+        }
+
+        const SLOT_SIZE: usize = 16;
+
+        let page_size = page_size();
+        let page = util::mmap_near(text, page_size)?;
+        let slots = Slots {
+            entry: page as usize,
+            exit: page as usize + SLOT_SIZE,
+            tailcall: page as usize + 2 * SLOT_SIZE,
+        };
+
+        unsafe {
+            arch::patch_slot(slots.entry as *mut u8, trampolines.entry);
+            arch::patch_slot(slots.exit as *mut u8, trampolines.exit);
+            arch::patch_slot(slots.tailcall as *mut u8, trampolines.tailcall);
+            libc::mprotect(page.cast(), page_size, libc::PROT_READ | libc::PROT_EXEC);
+        }
+
+        Some(slots)
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(target_os = "linux")))]
+    fn preload(_text: Range<usize>) -> Option<Slots> {
+        None
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn preload(_text: Range<usize>) -> Option<Slots> {
+        Some(Slots::trampolines())
+    }
+}
+
+/// Enable or disable recording on the current thread,
+/// this takes precedence over the thread filters.
+#[unsafe(no_mangle)]
+pub extern "C" fn sftrace_set_thread_enabled(enabled: bool) {
+    events::set_thread_enabled(enabled);
+}
+
+/// Copy the instrumented functions the current thread is in, outermost first,
+/// into `frames` and return the depth, which may be larger than `cap`.
+///
+/// # Safety
+///
+/// `frames` must be valid for `cap` writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sftrace_current_stack(frames: *mut events::Frame, cap: usize) -> usize {
+    unsafe { events::current_stack(frames, cap) }
+}
+
+/// Run `hook` after every entry into the function at `addr`, or remove it if null.
+///
+/// Returns the number of sleds of the function, 0 if it is not instrumented.
+#[unsafe(no_mangle)]
+pub extern "C" fn sftrace_on_entry(addr: usize, hook: Option<hook::EntryHook>) -> usize {
+    hook::set_entry(addr, hook)
+}
+
+/// Run `hook` before every exit from the function at `addr`, or remove it if null.
+///
+/// Exits through a tail call don't run it, there is no return value.
+#[unsafe(no_mangle)]
+pub extern "C" fn sftrace_on_exit(addr: usize, hook: Option<hook::ExitHook>) -> usize {
+    hook::set_exit(addr, hook)
+}
+
+/// Call `callback` on the slow calls instead of printing them, or print again if null.
+#[unsafe(no_mangle)]
+pub extern "C" fn sftrace_on_slow(callback: Option<slow::Callback>) {
+    slow::set_callback(callback);
+}
+
+/// Runtime address of a symbol of the patched object, 0 if not found.
+///
+/// # Safety
+///
+/// `name` must be a valid C string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sftrace_symbol_address(name: *const c_char) -> usize {
+    let Some((path, base)) = OBJECT.get() else {
+        return 0;
+    };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return 0;
+    };
+
+    let Ok(fd) = fs::File::open(path) else {
+        return 0;
+    };
+    let Ok(buf) = (unsafe { memmap2::Mmap::map(&fd) }) else {
+        return 0;
+    };
+    let Ok(obj) = object::File::parse(buf.as_ref()) else {
+        return 0;
+    };
+
+    symbol_addresses(&obj, &[name.into()])
+        .into_iter()
+        .next()
+        .map_or(0, |addr| base + addr as usize)
+}
+
+/// Runtime address of the function of a sled, 0 if unknown
+fn function_address(func_id: u32) -> usize {
+    FUNCTIONS
+        .get()
+        .and_then(|functions| functions.get(func_id as usize))
+        .copied()
+        .unwrap_or(0)
 }
 
 #[unsafe(no_mangle)]
@@ -43,14 +298,67 @@ pub extern "C" fn sftrace_alloc_event(kind: u8, size: usize, align: usize, ptr:
     events::record_alloc(kind, size, align, ptr);
 }
 
-static OUTPUT: OnceLock<fs::File> = OnceLock::new();
+#[unsafe(no_mangle)]
+pub extern "C" fn sftrace_unwind_event() {
+    events::record_unwind();
+}
 
-fn init(
-    entry_slot: unsafe extern "C" fn(),
-    exit_slot: unsafe extern "C" fn(),
-    tailcall_slot: unsafe extern "C" fn(),
-) {
-    patch_xray(entry_slot, exit_slot, tailcall_slot);
+fn load_config() -> Option<config::Config> {
+    match config::Config::from_env() {
+        Ok(config) => Some(config),
+        Err(err) => {
+            eprintln!("{:?}", err);
+            None
+        }
+    }
+}
+
+fn init(config: &config::Config, target: Target) {
+    SETUP_THREAD_ONLY.store(config.thread.setup_only, atomic::Ordering::Relaxed);
+    if let Some(filter) = config.thread.filter.as_ref() {
+        match regex::Regex::new(filter) {
+            Ok(filter) => {
+                let _ = THREAD_FILTER.set(filter);
+            }
+            Err(err) => eprintln!("bad thread filter: {:?}", err),
+        }
+    }
+    counter::CPU_TIME.store(config.record.cpu_time, atomic::Ordering::Relaxed);
+    if config.record.cpu && cfg!(not(target_os = "linux")) {
+        eprintln!("recording the cpu is only supported on linux");
+    }
+    counter::RECORD_CPU.store(config.record.cpu, atomic::Ordering::Relaxed);
+    let flag = counter::CounterFlag::parse(&config.record.sw_counters);
+    counter::SW_COUNTERS.store(flag.bits(), atomic::Ordering::Relaxed);
+    events::set_clock(config.record.clock);
+
+    match config.output.buffer_size.map(usize::try_from) {
+        Some(Ok(size)) if size != 0 => output::BUFFER_SIZE.store(size, atomic::Ordering::Relaxed),
+        Some(_) => eprintln!("bad buffer size: {:?}", config.output.buffer_size),
+        None => (),
+    }
+    let max_size = config.output.max_size.unwrap_or(0);
+    let rotate_size = config.output.rotate_size.unwrap_or(0);
+    output::MAX_SIZE.store(max_size, atomic::Ordering::Relaxed);
+    output::ROTATE_SIZE.store(rotate_size, atomic::Ordering::Relaxed);
+
+    // before patching, so that every recording thread registers its buffer,
+    // the buffers of the threads still alive are handed over at exit
+    if config.output.path().is_some() {
+        events::track_threads();
+
+        if config.output.crash_handler {
+            crash::install();
+        }
+
+        match config.output.flush_interval {
+            Some(interval) if !interval.is_zero() => events::spawn_flusher(interval),
+            Some(_) => eprintln!("bad flush interval: 0"),
+            None => (),
+        }
+    }
+
+    patch_xray(config, target);
 
     unsafe {
         match libc::atexit(shutdown) {
@@ -60,28 +368,32 @@ fn init(
     }
 }
 
-fn patch_xray(
-    entry_slot: unsafe extern "C" fn(),
-    exit_slot: unsafe extern "C" fn(),
-    tailcall_slot: unsafe extern "C" fn(),
-) {
+fn patch_xray(config: &config::Config, target: Target) {
     use findshlibs::{Segment, SharedLibrary};
     use zerocopy::FromBytes;
 
-    let Some(outfile) = std::env::var_os("SFTRACE_OUTPUT_FILE") else {
+    let Some(template) = config.output.path() else {
         return;
     };
 
     let page_size = page_size();
 
+    // The main executable comes first
+    let mut is_main = true;
+
     findshlibs::TargetSharedLibrary::each(|shlib| {
+        let main = std::mem::replace(&mut is_main, false);
         let base = shlib.actual_load_addr();
         let shlibid = match shlib.id() {
             Some(id) => id.as_bytes().to_owned(),
             None => return,
         };
 
-        if !(base.0..base.0 + shlib.len()).contains(&(entry_slot as usize)) {
+        let found = match target {
+            Target::Setup(slots) => (base.0..base.0 + shlib.len()).contains(&slots.entry),
+            Target::Main => main,
+        };
+        if !found {
             return;
         }
 
@@ -105,12 +417,13 @@ fn patch_xray(
             && shlibid != build_id
         {
             eprintln!("build id does not match: {:?} vs {:?}", shlibid, build_id);
+            diagnose(layout::Diagnostic::BuildIdMismatch);
             return;
         }
 
         let mut maybe_filter_buf = None;
-        if let Ok(path) = std::env::var("SFTRACE_FILTER") {
-            let fd = fs::File::open(&path).unwrap();
+        if let Some(path) = config.filter.path.as_ref() {
+            let fd = fs::File::open(path).unwrap();
             let buf = unsafe { memmap2::Mmap::map(&fd).unwrap() };
             maybe_filter_buf = Some(buf);
         }
@@ -118,78 +431,135 @@ fn patch_xray(
             .as_ref()
             .map(|buf| layout::FilterMap::parse(buf, obj.build_id().ok().flatten()).unwrap());
 
-        let Some((text_addr, text_len)) = shlib
+        let record_args = symbol_addresses(&obj, &config.record.args);
+        let triggers = symbol_addresses(&obj, &config.record.triggers);
+
+        let trigger_only = !triggers.is_empty()
+            || maybe_filter.is_some_and(|filter| filter.has_flag(layout::FuncFlag::TRIGGER));
+        TRIGGER_ONLY.store(trigger_only, atomic::Ordering::Relaxed);
+
+        let texts = shlib
             .segments()
             .filter(|seg| seg.is_code() && seg.len() != 0)
             .map(|seg| (seg.actual_virtual_memory_address(shlib), seg.len()))
-            .find(|(addr, len)| (addr.0..addr.0 + len).contains(&(entry_slot as usize)))
             .map(|(text_addr, text_len)| {
                 let addr = text_addr.0 & !(page_size - 1);
                 let len = text_addr.0 + text_len - addr;
                 let len = (len + page_size - 1) & !(page_size - 1);
-                (addr, len)
+                addr..addr + len
             })
+            .collect::<Vec<_>>();
+        let Some(text) = texts
+            .iter()
+            .map(|text| text.start)
+            .min()
+            .zip(texts.iter().map(|text| text.end).max())
+            .map(|(start, end)| start..end)
         else {
             return;
         };
 
-        {
-            use std::io;
-            use std::path::PathBuf;
-
-            let pid = std::process::id();
-
-            let mut path = PathBuf::from(outfile.clone());
-            let mut use_pid = true;
-
-            let mut fd = loop {
-                match fs::OpenOptions::new()
-                    .create_new(true)
-                    .append(true)
-                    .open(&path)
-                {
-                    Ok(fd) => break fd,
-                    Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
-                        path = if use_pid {
-                            use_pid = false;
-                            path.with_extension(pid.to_string())
-                        } else {
-                            use std::collections::hash_map::RandomState;
-                            use std::hash::BuildHasher;
-
-                            let rand = RandomState::new().hash_one(pid);
-
-                            path.with_extension(rand.to_string())
-                        };
-                    }
-                    Err(err) => panic!("open output file failed: {:?}", err),
+        let slots = match target {
+            _ if cfg!(target_arch = "aarch64") => Slots::trampolines(),
+            Target::Setup(slots) => slots,
+            Target::Main => match Slots::preload(text) {
+                Some(slots) => slots,
+                None => {
+                    eprintln!("no memory for the slots near the text segment");
+                    return;
                 }
+            },
+        };
+
+        {
+            let (path, fd) = match output::create(&template) {
+                Ok(output) => output,
+                Err(err) => panic!("open output file {:?} failed: {:?}", template, err),
             };
             let metadata = layout::Metadata {
                 shlibid,
-                pid,
+                pid: std::process::id(),
                 shlib_base: base.0 as u64,
                 shlib_path: shlib.name().into(),
             };
-            fd.write_all(layout::SIGN_TRACE).unwrap();
-            cbor4ii::serde::to_writer(&mut fd, &metadata).unwrap();
-            OUTPUT.set(fd).expect("already initialized");
+            let mut header = layout::SIGN_TRACE.to_vec();
+            cbor4ii::serde::to_writer(&mut header, &metadata).unwrap();
+            output::init(path, fd, header);
         }
 
-        let _guard = unsafe { MProtect::unlock(text_addr as *mut u8, text_len) };
+        let _guards = texts
+            .iter()
+            .map(|text| unsafe { MProtect::unlock(text.start as *mut u8, text.len()) })
+            .collect::<Vec<_>>();
 
         let entry_map = <[layout::XRayFunctionEntry]>::ref_from_bytes(buf.as_ref()).unwrap();
-        let (entry_slot, exit_slot, tailcall_slot) = if cfg!(target_arch = "aarch64") {
-            (
-                arch::xray_entry as _,
-                arch::xray_exit as _,
-                arch::xray_tailcall as _,
-            )
+
+        let mut summary = PatchSummary::default();
+        let mut sleds = Vec::new();
+
+        let entry_map = layout::XRayInstrMap::new(entry_map).relocate(&obj, xray_section.address());
+
+        let base = if cfg!(target_os = "macos") {
+            match obj.kind() {
+                object::ObjectKind::Executable => 0,
+                object::ObjectKind::Dynamic => base.0,
+                kind => {
+                    eprintln!("unsupported object kind: {:?}", kind);
+                    base.0
+                }
+            }
         } else {
-            (entry_slot, exit_slot, tailcall_slot)
+            base.0
         };
 
-        for entry in layout::XRayInstrMap(entry_map).iter(xray_section.address()) {
+        // known before any sled is live, the shadow stacks match exits by function
+        let fn_index = obj.section_by_name("xray_fn_idx").and_then(|section| {
+            let buf = section.uncompressed_data().ok()?;
+            layout::XRayFunctionIndex::parse(
+                &obj,
+                &buf,
+                section.address(),
+                &entry_map,
+                xray_section.address(),
+            )
+        });
+        let mut functions = vec![0; entry_map.len()];
+        for func in entry_map.functions(xray_section.address(), fn_index.as_ref()) {
+            let addr = base + usize::try_from(func.address).unwrap();
+            for idx in func.sleds {
+                functions[idx as usize] = addr;
+            }
+        }
+        if !config.record.slow.is_empty() {
+            let rules = config
+                .record
+                .slow
+                .iter()
+                .filter_map(|rule| match slow::Rule::parse(rule) {
+                    Ok(rule) => Some(rule),
+                    Err(err) => {
+                        eprintln!("bad slow call rule: {:?}", err);
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            let names = function_names(&obj, &entry_map, xray_section.address());
+            slow::init(&rules, &names);
+        }
+
+        let _ = FUNCTIONS.set(functions.into_boxed_slice());
+        let _ = OBJECT.set((shlib.name().into(), base));
+
+        for entry in entry_map.iter(xray_section.address()) {
+            // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/llvm/include/llvm/CodeGen/AsmPrinter.h#L338
+            let kind = entry.kind();
+            if !matches!(kind, 0..=2) {
+                eprintln!("unsupport kind: {}", kind);
+                summary.unsupported += 1;
+                continue;
+            }
+            let idx = usize::from(kind);
+
             let mut flag = layout::FuncFlag::empty();
 
             if let Some(filter) = maybe_filter {
@@ -197,55 +567,215 @@ fn patch_xray(
                     (layout::FilterMode::MARK, Some(mark)) => flag |= mark.flag(),
                     (layout::FilterMode::MARK, _) => (),
                     (layout::FilterMode::FILTER, Some(mark)) => flag |= mark.flag(),
-                    (layout::FilterMode::FILTER, None) => continue,
-                    (..) => continue,
+                    (..) => {
+                        summary.filtered[idx] += 1;
+                        continue;
+                    }
                 }
             }
 
+            if record_args.contains(&entry.function()) {
+                flag |= layout::FuncFlag::LOG;
+            }
+
+            if triggers.contains(&entry.function()) {
+                flag |= layout::FuncFlag::TRIGGER;
+            }
+
             let func_id = FuncId::pack(entry.id(), flag).unwrap();
             let func_id = func_id.0;
 
-            let base = if cfg!(target_os = "macos") {
-                match obj.kind() {
-                    object::ObjectKind::Executable => 0,
-                    object::ObjectKind::Dynamic => base.0,
-                    kind => {
-                        eprintln!("unsupported object kind: {:?}", kind);
-                        base.0
-                    }
-                }
-            } else {
-                base.0
-            };
             let addr: usize = entry.address().try_into().unwrap();
             let addr = base + addr;
 
-            // https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/llvm/include/llvm/CodeGen/AsmPrinter.h#L338
+            // A wrong base or a mismatched binary must not corrupt the code
+            if !texts
+                .iter()
+                .any(|text| text.contains(&addr) && addr + arch::SLED_SIZE <= text.end)
+                || !unsafe { arch::check_sled(addr, kind) }
+            {
+                summary.invalid[idx] += 1;
+                continue;
+            }
+
             unsafe {
-                match entry.kind() {
-                    // entry
-                    0 => arch::patch_entry(addr, func_id, entry_slot),
-                    // exit
-                    1 => arch::patch_exit(addr, func_id, exit_slot),
-                    // tail call
-                    2 => arch::patch_tailcall(addr, func_id, tailcall_slot),
-                    kind => eprintln!("unsupport kind: {}", kind),
+                match kind {
+                    0 => arch::patch_entry(addr, func_id, slots.entry),
+                    1 => arch::patch_exit(addr, func_id, slots.exit),
+                    _ => arch::patch_tailcall(addr, func_id, slots.tailcall),
                 }
             }
+            summary.patched[idx] += 1;
+            if kind == 0 && flag.contains(layout::FuncFlag::TRIGGER) {
+                summary.triggers += 1;
+            }
+            sleds.push((addr, kind));
+        }
+
+        PATCHED.lock().unwrap().push(Patched {
+            texts: texts.clone(),
+            sleds,
+        });
+
+        let invalid = summary.invalid.iter().sum::<usize>();
+        if invalid != 0 {
+            eprintln!(
+                "{} xray sleds do not match the expected pattern, skipped",
+                invalid
+            );
+            diagnose(layout::Diagnostic::InvalidSleds { count: invalid });
+        }
+
+        if trigger_only && summary.triggers == 0 {
+            eprintln!("no trigger function is patched, nothing will be recorded");
+            diagnose(layout::Diagnostic::NoTrigger);
+        }
+
+        diagnose(layout::Diagnostic::Patched {
+            entry: summary.patched[0],
+            exit: summary.patched[1],
+            tail_call: summary.patched[2],
+        });
+
+        if config.verbose {
+            eprintln!("sftrace: {}", shlib.name().to_string_lossy());
+            summary.print();
         }
 
         #[cfg(not(target_arch = "aarch64"))]
-        unsafe {
-            arch::patch_slot(entry_slot as *mut u8, arch::xray_entry as *const () as usize);
-            arch::patch_slot(exit_slot as *mut u8, arch::xray_exit as *const () as usize);
-            arch::patch_slot(tailcall_slot as *mut u8, arch::xray_tailcall as *const () as usize);
+        if let Target::Setup(slots) = target {
+            let trampolines = Slots::trampolines();
+
+            unsafe {
+                arch::patch_slot(slots.entry as *mut u8, trampolines.entry);
+                arch::patch_slot(slots.exit as *mut u8, trampolines.exit);
+                arch::patch_slot(slots.tailcall as *mut u8, trampolines.tailcall);
+            }
         }
     });
 }
 
+/// Demangled name of the function of every sled, by sled index
+fn function_names(
+    obj: &object::File,
+    entry_map: &layout::XRayInstrMap,
+    section_offset: u64,
+) -> Vec<Option<String>> {
+    let functions = entry_map
+        .iter(section_offset)
+        .map(|entry| entry.function())
+        .collect::<HashSet<_>>();
+    let symbols = obj
+        .symbols()
+        .filter(|sym| functions.contains(&sym.address()))
+        .filter_map(|sym| Some((sym.address(), sym.name().ok()?)))
+        .collect::<std::collections::HashMap<_, _>>();
+
+    let mut names = Vec::new();
+    for entry in entry_map.iter(section_offset) {
+        let idx = entry.id() as usize;
+        if names.len() <= idx {
+            names.resize(idx + 1, None);
+        }
+        names[idx] = symbols
+            .get(&entry.function())
+            .map(|name| format!("{:#}", rustc_demangle::demangle(name)));
+    }
+    names
+}
+
+/// Addresses of the symbols named by their raw name,
+/// or by their demangled Rust path without the hash as printed by `sftrace list`.
+fn symbol_addresses(obj: &object::File, names: &[String]) -> HashSet<u64> {
+    if names.is_empty() {
+        return HashSet::new();
+    }
+
+    let names = names.iter().map(String::as_str).collect::<HashSet<_>>();
+    let matches = |name: &str| {
+        names.contains(name)
+            || rustc_demangle::try_demangle(name)
+                .is_ok_and(|demangled| names.contains(&*format!("{:#}", demangled)))
+    };
+    obj.symbols()
+        .filter(|sym| sym.name().is_ok_and(matches))
+        .map(|sym| sym.address())
+        .collect()
+}
+
+/// Print a diagnostic for `sftrace doctor`, if it runs us.
+fn diagnose(diagnostic: layout::Diagnostic) {
+    if std::env::var_os(layout::DIAGNOSTIC_ENV).is_none() {
+        return;
+    }
+
+    if let Ok(line) = serde_json::to_string(&diagnostic) {
+        eprintln!("{}{}", layout::DIAGNOSTIC_PREFIX, line);
+    }
+}
+
+/// Called by `sftrace_detach` and again at exit
 extern "C" fn shutdown() {
-    // TODO flush all thread ?
-    events::flush_current_thread();
+    static SHUTDOWN: Once = Once::new();
+
+    SHUTDOWN.call_once(|| {
+        events::flush_current_thread();
+        events::flush_threads(true);
+        output::shutdown();
+    });
+}
+
+struct Patched {
+    texts: Vec<Range<usize>>,
+    sleds: Vec<(usize, u8)>,
+}
+
+impl Patched {
+    fn restore(&self) {
+        let _guards = self
+            .texts
+            .iter()
+            .map(|text| unsafe { MProtect::unlock(text.start as *mut u8, text.len()) })
+            .collect::<Vec<_>>();
+
+        for &(addr, kind) in &self.sleds {
+            unsafe {
+                match kind {
+                    0 => arch::unpatch_entry(addr),
+                    1 => arch::unpatch_exit(addr),
+                    _ => arch::unpatch_tailcall(addr),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct PatchSummary {
+    patched: [usize; 3],
+    filtered: [usize; 3],
+    invalid: [usize; 3],
+    unsupported: usize,
+    triggers: usize,
+}
+
+impl PatchSummary {
+    fn print(&self) {
+        for (idx, name) in ["entry", "exit", "tail call"].iter().enumerate() {
+            eprintln!(
+                "  {:<9}: {} patched, {} filtered, {} skipped",
+                name, self.patched[idx], self.filtered[idx], self.invalid[idx]
+            );
+        }
+
+        if self.unsupported != 0 {
+            eprintln!("  unsupported: {}", self.unsupported);
+        }
+
+        if self.triggers != 0 {
+            eprintln!("  triggers : {}", self.triggers);
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
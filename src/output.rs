@@ -0,0 +1,581 @@
+use crate::layout::{self, ChunkHeader};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicI32, AtomicU64, AtomicUsize};
+use std::sync::{OnceLock, mpsc};
+use std::{fs, thread};
+use zerocopy::IntoBytes;
+
+pub static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(4 * 1024);
+/// Stop recording once the output reaches this size, 0 is unlimited
+pub static MAX_SIZE: AtomicU64 = AtomicU64::new(0);
+/// Roll over to a new file once the current one reaches this size, 0 is never
+pub static ROTATE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+static LIMIT_REACHED: AtomicBool = AtomicBool::new(false);
+/// Events dropped after the thread local buffer was destroyed
+static TEARDOWN: AtomicU64 = AtomicU64::new(0);
+
+/// The file currently written, for the crash handler
+static OUTPUT_FD: AtomicI32 = AtomicI32::new(-1);
+/// Chunks sent to the writer, and chunks it has written out
+static SENT: AtomicU64 = AtomicU64::new(0);
+static FLUSHED: AtomicU64 = AtomicU64::new(0);
+
+static WRITER: OnceLock<Writer> = OnceLock::new();
+
+struct Writer {
+    pid: u32,
+    sender: mpsc::Sender<Message>,
+}
+
+pub struct Chunk {
+    pub tid: u32,
+    pub buf: Vec<u8>,
+    pub events: u32,
+    /// events the thread dropped since its last chunk
+    pub dropped: layout::DroppedEvent,
+    /// The writer hands the buffer back to its thread through this
+    pub back: mpsc::Sender<Vec<u8>>,
+}
+
+enum Message {
+    Chunk(Chunk),
+    Shutdown(mpsc::SyncSender<()>),
+}
+
+pub fn buffer_size() -> usize {
+    BUFFER_SIZE.load(atomic::Ordering::Relaxed)
+}
+
+pub fn is_enabled() -> bool {
+    WRITER.get().is_some()
+}
+
+pub fn limit_reached() -> bool {
+    LIMIT_REACHED.load(atomic::Ordering::Relaxed)
+}
+
+pub fn count_teardown() {
+    TEARDOWN.fetch_add(1, atomic::Ordering::Relaxed);
+}
+
+/// Expand the placeholders of the output path template and create the file.
+///
+/// If the path is taken and the template has no `%n`,
+/// the pid and then a random number are inserted before the extension.
+pub fn create(template: &Path) -> io::Result<(PathBuf, fs::File)> {
+    let pid = std::process::id();
+    let time = start_time();
+    let exe = std::env::current_exe()
+        .ok()
+        .and_then(|path| path.file_name().map(|name| name.as_bytes().to_vec()))
+        .unwrap_or_else(|| b"unknown".to_vec());
+    let host = hostname();
+
+    let expand = |seq: u32| {
+        let mut path = Vec::new();
+        let mut iter = template.as_os_str().as_bytes().iter();
+
+        while let Some(&c) = iter.next() {
+            if c != b'%' {
+                path.push(c);
+                continue;
+            }
+
+            match iter.next() {
+                Some(b'p') => path.extend_from_slice(pid.to_string().as_bytes()),
+                Some(b't') => path.extend_from_slice(time.as_bytes()),
+                Some(b'e') => path.extend_from_slice(&exe),
+                Some(b'h') => path.extend_from_slice(&host),
+                Some(b'n') => path.extend_from_slice(seq.to_string().as_bytes()),
+                Some(&c) => path.push(c),
+                None => path.push(b'%'),
+            }
+        }
+
+        PathBuf::from(OsString::from_vec(path))
+    };
+    let open = |path: &Path| {
+        fs::OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(path)
+    };
+
+    let path = expand(0);
+    if let Some(dir) = path.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        fs::create_dir_all(dir)?;
+    }
+
+    let has_seq = path != expand(1);
+    let mut seq = 0;
+    let mut path = path;
+    loop {
+        match open(&path) {
+            Ok(fd) => return Ok((path, fd)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && has_seq => {
+                seq += 1;
+                path = expand(seq);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    let with_suffix = |suffix: &str| {
+        let mut name = path.file_stem().unwrap_or_default().to_owned();
+        name.push(".");
+        name.push(suffix);
+        if let Some(ext) = path.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+        path.with_file_name(name)
+    };
+
+    let path = with_suffix(&pid.to_string());
+    match open(&path) {
+        Ok(fd) => Ok((path, fd)),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            use std::collections::hash_map::RandomState;
+            use std::hash::BuildHasher;
+
+            let rand = RandomState::new().hash_one(pid);
+            let path = with_suffix(&rand.to_string());
+            open(&path).map(|fd| (path, fd))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Local time like `20250102-150405`
+fn start_time() -> String {
+    let mut buf = [0u8; 32];
+
+    let n = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            c"%Y%m%d-%H%M%S".as_ptr(),
+            &tm,
+        )
+    };
+
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+fn hostname() -> Vec<u8> {
+    let mut buf = [0u8; 256];
+
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return b"unknown".to_vec();
+    }
+
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    buf[..len].to_vec()
+}
+
+/// Start the writer thread, `header` is written at the start of every output file.
+pub fn init(path: PathBuf, fd: fs::File, header: Vec<u8>) {
+    // `sftrace record` prints the resolved paths at exit
+    if let Some(list) = std::env::var_os("SFTRACE_OUTPUT_LIST") {
+        let line = [path.as_os_str().as_bytes(), b"\n"].concat();
+        let result = fs::OpenOptions::new()
+            .append(true)
+            .open(list)
+            .and_then(|mut fd| fd.write_all(&line));
+
+        if let Err(err) = result {
+            eprintln!("write output list failed: {:?}", err);
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("sftrace-writer".into())
+        .spawn(move || writer(path, fd, header, receiver))
+        .expect("spawn writer thread failed");
+
+    let writer = Writer {
+        pid: std::process::id(),
+        sender,
+    };
+
+    if WRITER.set(writer).is_err() {
+        panic!("already initialized");
+    }
+}
+
+pub fn send(chunk: Chunk) {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+
+    // The writer thread does not survive fork, nothing would drain the channel.
+    // The child shares the file with the parent, write the chunk out directly.
+    if writer.pid != std::process::id() {
+        let Chunk {
+            tid, mut buf, back, ..
+        } = chunk;
+        write_raw(tid, &buf);
+        buf.clear();
+        let _ = back.send(buf);
+        return;
+    }
+
+    SENT.fetch_add(1, atomic::Ordering::Relaxed);
+    let _ = writer.sender.send(Message::Chunk(chunk));
+}
+
+/// Wait until the writer has written out the chunks sent so far, for up to `millis`.
+///
+/// Only loads atomics and calls `nanosleep`, so it can be used in a signal handler.
+pub fn wait_flushed(millis: u32) {
+    let sent = SENT.load(atomic::Ordering::Relaxed);
+    let tick = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 1_000_000,
+    };
+
+    for _ in 0..millis {
+        if FLUSHED.load(atomic::Ordering::Acquire) >= sent {
+            break;
+        }
+
+        unsafe {
+            libc::nanosleep(&tick, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Write a chunk straight to the output file, bypassing the writer thread.
+///
+/// Used by the crash handler, so no allocation and no locks, and by forked children.
+pub fn write_raw(tid: u32, buf: &[u8]) {
+    let fd = OUTPUT_FD.load(atomic::Ordering::Acquire);
+    let Ok(len) = u32::try_from(buf.len()) else {
+        return;
+    };
+    if fd < 0 || buf.is_empty() {
+        return;
+    }
+
+    let header = ChunkHeader {
+        tid: tid.into(),
+        len: len.into(),
+    };
+    let iov = [
+        libc::iovec {
+            iov_base: header.as_bytes().as_ptr() as *mut _,
+            iov_len: header.as_bytes().len(),
+        },
+        libc::iovec {
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len(),
+        },
+    ];
+
+    // a short write leaves a truncated chunk, which the reader ignores with a warning
+    unsafe {
+        libc::writev(fd, iov.as_ptr(), iov.len() as _);
+    }
+}
+
+/// Wait for the writer to drain all chunks sent before this call and finish the output.
+pub fn shutdown() {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+
+    // The writer thread does not survive fork
+    if writer.pid != std::process::id() {
+        return;
+    }
+
+    let (tx, rx) = mpsc::sync_channel(1);
+    if writer.sender.send(Message::Shutdown(tx)).is_ok() {
+        let _ = rx.recv();
+    }
+}
+
+struct Sink {
+    path: PathBuf,
+    header: Vec<u8>,
+    output: io::BufWriter<fs::File>,
+    rotate: u32,
+    file_size: u64,
+    total_size: u64,
+    /// per thread, reported at shutdown
+    dropped: BTreeMap<u32, layout::DroppedEvent>,
+}
+
+impl Sink {
+    const CAP: usize = 64 * 1024;
+
+    fn new(path: PathBuf, fd: fs::File, header: Vec<u8>) -> io::Result<Sink> {
+        OUTPUT_FD.store(fd.as_raw_fd(), atomic::Ordering::Release);
+        let mut output = io::BufWriter::with_capacity(Self::CAP, fd);
+        output.write_all(&header)?;
+
+        let size = header.len() as u64;
+        Ok(Sink {
+            path,
+            header,
+            output,
+            rotate: 0,
+            file_size: size,
+            total_size: size,
+            dropped: BTreeMap::new(),
+        })
+    }
+
+    fn write_chunk(
+        &mut self,
+        tid: u32,
+        buf: &[u8],
+        events: u32,
+        dropped: &layout::DroppedEvent,
+    ) -> io::Result<()> {
+        if dropped.total() != 0 {
+            self.dropped.entry(tid).or_default().add(dropped);
+        }
+
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let Ok(len) = u32::try_from(buf.len()) else {
+            eprintln!("chunk too large: {}", buf.len());
+            return Ok(());
+        };
+        let size = (std::mem::size_of::<ChunkHeader>() + buf.len()) as u64;
+
+        let max_size = MAX_SIZE.load(atomic::Ordering::Relaxed);
+        if limit_reached() || (max_size != 0 && self.total_size + size > max_size) {
+            if !LIMIT_REACHED.swap(true, atomic::Ordering::Relaxed) {
+                eprintln!("output size limit reached, stop recording");
+            }
+
+            self.dropped.entry(tid).or_default().limit += u64::from(events);
+            return Ok(());
+        }
+
+        let rotate_size = ROTATE_SIZE.load(atomic::Ordering::Relaxed);
+        if rotate_size != 0
+            && self.file_size > self.header.len() as u64
+            && self.file_size + size > rotate_size
+        {
+            self.rotate()?;
+        }
+
+        let header = ChunkHeader {
+            tid: tid.into(),
+            len: len.into(),
+        };
+        self.output.write_all(header.as_bytes())?;
+        self.output.write_all(buf)?;
+        self.file_size += size;
+        self.total_size += size;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.output.flush()?;
+        self.rotate += 1;
+
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", self.rotate));
+        let fd = fs::OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+
+        OUTPUT_FD.store(fd.as_raw_fd(), atomic::Ordering::Release);
+        self.output = io::BufWriter::with_capacity(Self::CAP, fd);
+        self.output.write_all(&self.header)?;
+        self.file_size = self.header.len() as u64;
+        self.total_size += self.file_size;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let teardown = TEARDOWN.swap(0, atomic::Ordering::Relaxed);
+        if teardown != 0 {
+            self.dropped.entry(layout::WRITER_TID).or_default().teardown += teardown;
+        }
+
+        let mut total = layout::DroppedEvent::default();
+        for (&tid, dropped) in &std::mem::take(&mut self.dropped) {
+            total.add(dropped);
+
+            let event: layout::Event<(), (), ()> = layout::Event {
+                tid,
+                func_id: 0,
+                time: crate::events::now(),
+                kind: layout::Kind::DROPPED,
+                args: None,
+                return_value: None,
+                alloc_event: None,
+                cpu_time: None,
+                counters: None,
+                dropped: Some(*dropped),
+                crash: None,
+                thread: None,
+                cpu: None,
+            };
+            let mut buf = Vec::new();
+            cbor4ii::serde::to_writer(&mut buf, &event).unwrap();
+
+            // the trailer is written even past the limit
+            let header = ChunkHeader {
+                tid: tid.into(),
+                len: (buf.len() as u32).into(),
+            };
+            self.output.write_all(header.as_bytes())?;
+            self.output.write_all(&buf)?;
+        }
+
+        if total.total() != 0 {
+            eprintln!(
+                "{} events dropped: {} reentrant, {} teardown, {} output size limit",
+                total.total(),
+                total.reentrant,
+                total.teardown,
+                total.limit
+            );
+        }
+
+        self.output.flush()
+    }
+}
+
+fn writer(path: PathBuf, fd: fs::File, header: Vec<u8>, receiver: mpsc::Receiver<Message>) {
+    let result: io::Result<()> = (|| {
+        let mut sink = Sink::new(path, fd, header)?;
+        let mut written = 0;
+
+        loop {
+            let msg = match receiver.try_recv() {
+                Ok(msg) => msg,
+                Err(mpsc::TryRecvError::Empty) => {
+                    // idle, don't keep chunks in memory
+                    sink.output.flush()?;
+                    FLUSHED.store(written, atomic::Ordering::Release);
+
+                    match receiver.recv() {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    }
+                }
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            };
+
+            match msg {
+                Message::Chunk(Chunk {
+                    tid,
+                    mut buf,
+                    events,
+                    dropped,
+                    back,
+                }) => {
+                    sink.write_chunk(tid, &buf, events, &dropped)?;
+                    written += 1;
+
+                    buf.clear();
+                    let _ = back.send(buf);
+                }
+                Message::Shutdown(ack) => {
+                    sink.finish()?;
+                    let _ = ack.send(());
+                }
+            }
+        }
+
+        sink.finish()
+    })();
+    OUTPUT_FD.store(-1, atomic::Ordering::Release);
+
+    if let Err(err) = result {
+        eprintln!("write output file failed: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(path: &Path) -> String {
+        path.file_name().unwrap().to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_create_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid = std::process::id();
+        let exe = std::env::current_exe().unwrap();
+        let exe = name(&exe);
+        let host = String::from_utf8(hostname()).unwrap();
+
+        let (path, _) = create(&dir.path().join("sub/sf-%p-%e-%h-%%.log")).unwrap();
+        assert_eq!(path.parent().unwrap(), dir.path().join("sub"));
+        assert_eq!(name(&path), format!("sf-{}-{}-{}-%.log", pid, exe, host));
+
+        let (path, _) = create(&dir.path().join("sf-%t.log")).unwrap();
+        let time = name(&path);
+        let time = time
+            .strip_prefix("sf-")
+            .unwrap()
+            .strip_suffix(".log")
+            .unwrap();
+        assert_eq!(time.len(), "20250102-150405".len());
+        assert!(time.bytes().all(|c| c.is_ascii_digit() || c == b'-'));
+    }
+
+    #[test]
+    fn test_create_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("sf-%n.log");
+
+        for seq in 0..3 {
+            let (path, _) = create(&template).unwrap();
+            assert_eq!(name(&path), format!("sf-{}.log", seq));
+        }
+
+        // the first free number, not the next after the highest
+        fs::remove_file(dir.path().join("sf-1.log")).unwrap();
+        let (path, _) = create(&template).unwrap();
+        assert_eq!(name(&path), "sf-1.log");
+    }
+
+    #[test]
+    fn test_create_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("sf.log");
+        let pid = std::process::id();
+
+        let (path, _) = create(&template).unwrap();
+        assert_eq!(path, template);
+
+        let (path, _) = create(&template).unwrap();
+        assert_eq!(name(&path), format!("sf.{}.log", pid));
+
+        let (path, _) = create(&template).unwrap();
+        assert!(path.exists());
+        assert_ne!(name(&path), "sf.log");
+        assert_ne!(name(&path), format!("sf.{}.log", pid));
+    }
+}
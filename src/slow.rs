@@ -0,0 +1,151 @@
+//! Report the calls of chosen functions that take longer than a budget, as they happen.
+
+use crate::config::parse_duration;
+use std::ffi::{CString, c_char};
+use std::sync::OnceLock;
+use std::sync::atomic::{self, AtomicUsize};
+use std::time::Duration;
+
+/// A `<regex>:<duration>` rule
+pub struct Rule {
+    pub pattern: regex::Regex,
+    pub budget: Duration,
+}
+
+impl Rule {
+    pub fn parse(s: &str) -> anyhow::Result<Rule> {
+        let (pattern, budget) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::format_err!("expect <regex>:<duration>: {:?}", s))?;
+        let pattern = regex::Regex::new(pattern)?;
+        let budget = parse_duration(budget)
+            .ok_or_else(|| anyhow::format_err!("bad duration: {:?}", budget))?;
+
+        Ok(Rule { pattern, budget })
+    }
+}
+
+struct Watched {
+    name: CString,
+    budget: u64,
+}
+
+struct Watchdog {
+    functions: Vec<Watched>,
+    /// index + 1 in `functions` by sled index, 0 is not watched
+    sleds: Box<[u32]>,
+}
+
+static WATCHDOG: OnceLock<Watchdog> = OnceLock::new();
+
+/// Set by `sftrace_on_slow`, prints to stderr if unset
+static CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+pub type Callback = extern "C" fn(&SlowCall);
+
+/// A call over its budget, passed to the callback
+#[repr(C)]
+pub struct SlowCall {
+    /// the entry sled id, as in the trace
+    pub func_id: u32,
+    /// the sftrace thread id, `u32::MAX` if the thread has not recorded anything
+    pub tid: u32,
+    pub duration_ns: u64,
+    pub budget_ns: u64,
+    /// demangled function name
+    pub name: *const c_char,
+}
+
+/// Watch the sleds whose function name matches a rule.
+///
+/// `names` gives the demangled name of the function of each sled, by sled index.
+pub fn init(rules: &[Rule], names: &[Option<String>]) {
+    let mut functions = Vec::new();
+    let mut by_name = std::collections::HashMap::new();
+
+    let sleds = names
+        .iter()
+        .map(|name| {
+            let Some(name) = name else {
+                return 0;
+            };
+            let Some(rule) = rules.iter().find(|rule| rule.pattern.is_match(name)) else {
+                return 0;
+            };
+
+            *by_name.entry(name.as_str()).or_insert_with(|| {
+                functions.push(Watched {
+                    name: CString::new(name.as_str()).unwrap_or_default(),
+                    budget: rule.budget.as_nanos() as u64,
+                });
+                functions.len() as u32
+            })
+        })
+        .collect();
+
+    if functions.is_empty() {
+        eprintln!("no function matches the slow call rules");
+        return;
+    }
+
+    let _ = WATCHDOG.set(Watchdog { functions, sleds });
+}
+
+pub fn is_enabled() -> bool {
+    WATCHDOG.get().is_some()
+}
+
+pub fn set_callback(callback: Option<Callback>) {
+    let callback = callback.map_or(0, |callback| callback as usize);
+    CALLBACK.store(callback, atomic::Ordering::Release);
+}
+
+/// Whether the function of the sled is watched and `duration` is over its budget
+#[inline]
+pub fn is_slow(func_id: u32, duration: u64) -> bool {
+    WATCHDOG
+        .get()
+        .is_some_and(|watchdog| match watchdog.sleds.get(func_id as usize) {
+            Some(&idx) if idx != 0 => duration > watchdog.functions[idx as usize - 1].budget,
+            _ => false,
+        })
+}
+
+/// Called outside of the recorder, the callback may run instrumented code.
+pub fn report(func_id: u32, duration: u64, tid: Option<u32>) {
+    let Some(watchdog) = WATCHDOG.get() else {
+        return;
+    };
+    let Some(&idx) = watchdog.sleds.get(func_id as usize) else {
+        return;
+    };
+    let Some(watched) = (idx as usize)
+        .checked_sub(1)
+        .map(|idx| &watchdog.functions[idx])
+    else {
+        return;
+    };
+
+    let callback = CALLBACK.load(atomic::Ordering::Acquire);
+    if callback != 0 {
+        let callback: Callback = unsafe { std::mem::transmute(callback) };
+        callback(&SlowCall {
+            func_id,
+            tid: tid.unwrap_or(u32::MAX),
+            duration_ns: duration,
+            budget_ns: watched.budget,
+            name: watched.name.as_ptr(),
+        });
+        return;
+    }
+
+    let thread = crate::util::thread_name().unwrap_or_default();
+    eprintln!(
+        "sftrace: slow call {} took {:?} (budget {:?}) on thread {} {:?}",
+        watched.name.to_string_lossy(),
+        Duration::from_nanos(duration),
+        Duration::from_nanos(watched.budget),
+        tid.map_or_else(|| "-".into(), |tid| tid.to_string()),
+        thread,
+    );
+}
@@ -0,0 +1,106 @@
+//! Remember which thread spawned the current one, by wrapping `pthread_create`.
+
+use std::cell::Cell;
+
+thread_local! {
+    /// The spawning thread and the time it called `pthread_create`
+    static PARENT: Cell<Option<(u32, u64)>> = const { Cell::new(None) };
+}
+
+pub fn parent() -> Option<(u32, u64)> {
+    PARENT.try_with(Cell::get).ok().flatten()
+}
+
+#[cfg(target_os = "linux")]
+mod wrap {
+    use super::PARENT;
+    use crate::{events, output};
+    use std::ffi::{c_int, c_void};
+    use std::sync::OnceLock;
+
+    // may be left by `pthread_exit` or cancellation
+    type Start = unsafe extern "C-unwind" fn(*mut c_void) -> *mut c_void;
+    type PthreadCreate = unsafe extern "C" fn(
+        *mut libc::pthread_t,
+        *const libc::pthread_attr_t,
+        Start,
+        *mut c_void,
+    ) -> c_int;
+
+    struct Spawn {
+        start: Start,
+        arg: *mut c_void,
+        parent: (u32, u64),
+    }
+
+    /// Takes over `pthread_create` for the objects loaded after us,
+    /// which is the main executable under `LD_PRELOAD` or when linked to `libsftrace.so`.
+    ///
+    /// This happens even when nothing is recorded, then it only calls through.
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn pthread_create(
+        thread: *mut libc::pthread_t,
+        attr: *const libc::pthread_attr_t,
+        start: Start,
+        arg: *mut c_void,
+    ) -> c_int {
+        static REAL: OnceLock<usize> = OnceLock::new();
+
+        let real = *REAL.get_or_init(real_pthread_create);
+        if real == 0 {
+            return libc::EAGAIN;
+        }
+        let real: PthreadCreate = unsafe { std::mem::transmute(real) };
+
+        // not recording or nothing to link the new thread to,
+        // the thread local is left alone when tracing is off
+        let parent = match output::is_enabled().then(events::current_tid).flatten() {
+            Some(tid) => (tid, events::now()),
+            None => return unsafe { real(thread, attr, start, arg) },
+        };
+
+        let spawn = Box::into_raw(Box::new(Spawn { start, arg, parent }));
+        let ret = unsafe { real(thread, attr, trampoline, spawn.cast()) };
+        if ret != 0 {
+            drop(unsafe { Box::from_raw(spawn) });
+        }
+        ret
+    }
+
+    /// The next `pthread_create` in the lookup order, or the one of libc itself
+    /// if `RTLD_NEXT` finds nothing, as when we are loaded by `dlopen`.
+    fn real_pthread_create() -> usize {
+        let name = c"pthread_create".as_ptr();
+
+        let real = unsafe { libc::dlsym(libc::RTLD_NEXT, name) };
+        if !real.is_null() {
+            return real as usize;
+        }
+
+        // libpthread before glibc 2.34
+        for lib in [c"libc.so.6", c"libpthread.so.0"] {
+            unsafe {
+                let handle = libc::dlopen(lib.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+                if handle.is_null() {
+                    continue;
+                }
+
+                let real = libc::dlsym(handle, name);
+                libc::dlclose(handle);
+                if !real.is_null() && !std::ptr::eq(real, pthread_create as *const c_void) {
+                    return real as usize;
+                }
+            }
+        }
+
+        eprintln!("sftrace: the real pthread_create is not found");
+        0
+    }
+
+    unsafe extern "C-unwind" fn trampoline(spawn: *mut c_void) -> *mut c_void {
+        let Spawn { start, arg, parent } = *unsafe { Box::from_raw(spawn.cast::<Spawn>()) };
+
+        let _ = PARENT.try_with(|cell| cell.set(Some(parent)));
+        unsafe { start(arg) }
+    }
+}
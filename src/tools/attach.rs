@@ -0,0 +1,451 @@
+use crate::config::parse_duration;
+use crate::record::search_sftracelib;
+use anyhow::Context;
+use argh::FromArgs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Attach to a running process
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "attach")]
+pub struct SubCommand {
+    /// the process id
+    #[argh(option)]
+    pid: i32,
+
+    /// output log file
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+
+    /// capture config file
+    #[argh(option, short = 'c')]
+    config: Option<PathBuf>,
+
+    /// restore the process after this duration, like `10s`, otherwise record until it exits
+    #[argh(option, from_str_fn(duration))]
+    duration: Option<Duration>,
+
+    /// the `libsftrace.so` path
+    #[argh(option)]
+    solib: Option<PathBuf>,
+}
+
+fn duration(s: &str) -> Result<Duration, String> {
+    parse_duration(s).ok_or_else(|| format!("bad duration: {:?}", s))
+}
+
+impl SubCommand {
+    #[cfg(not(target_os = "linux"))]
+    pub fn exec(self) -> anyhow::Result<()> {
+        anyhow::bail!("attach is only supported on linux")
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn exec(self) -> anyhow::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let projdir =
+            directories::ProjectDirs::from("", "", "sftrace").context("not found project dir")?;
+        let solib = match self.solib.as_ref() {
+            Some(p) => p.clone(),
+            None => search_sftracelib(projdir.data_dir())?,
+        };
+
+        // the paths are resolved by the traced process
+        let solib = solib.canonicalize()?;
+        let output = std::path::absolute(&self.output)?;
+        let config = self.config.as_ref().map(std::path::absolute).transpose()?;
+
+        let cstr = |path: &std::path::Path| CString::new(path.as_os_str().as_bytes());
+        let solib_cstr = cstr(&solib)?;
+        let output_cstr = cstr(&output)?;
+        let config_cstr = config.as_deref().map(cstr).transpose()?;
+
+        let pid = self.pid;
+        let dlopen = ptrace::lookup(pid, "dlopen", ptrace::is_libc)?;
+        let dlerror = ptrace::lookup(pid, "dlerror", ptrace::is_libc)?;
+
+        {
+            let tracee = ptrace::Tracee::attach(pid)?;
+
+            let handle = tracee.call(
+                dlopen,
+                &[
+                    ptrace::Arg::Str(&solib_cstr),
+                    ptrace::Arg::Int(libc::RTLD_NOW as u64),
+                ],
+            )?;
+            if handle == 0 {
+                let err = tracee.call(dlerror, &[])?;
+                let err = tracee.read_cstr(err)?;
+                anyhow::bail!("dlopen {:?} failed: {}", solib, err);
+            }
+
+            let attach = ptrace::lookup(pid, "sftrace_attach", |path| path == solib)?;
+            let ret = tracee.call(
+                attach,
+                &[
+                    ptrace::Arg::Str(&output_cstr),
+                    match config_cstr.as_ref() {
+                        Some(config) => ptrace::Arg::Str(config),
+                        None => ptrace::Arg::Int(0),
+                    },
+                ],
+            )?;
+            if ret as i32 != 0 {
+                anyhow::bail!("sftrace_attach failed, see the stderr of {}", pid);
+            }
+        }
+
+        eprintln!("recording {} to {}", pid, output.display());
+
+        let Some(duration) = self.duration else {
+            return Ok(());
+        };
+
+        std::thread::sleep(duration);
+
+        let detach = ptrace::lookup(pid, "sftrace_detach", |path| path == solib)?;
+        let tracee = ptrace::Tracee::attach(pid)?;
+        tracee.call(detach, &[])?;
+
+        eprintln!("detached from {}", pid);
+
+        Ok(())
+    }
+}
+
+/// Call functions in another process, the way a debugger does.
+#[cfg(target_os = "linux")]
+mod ptrace {
+    use anyhow::Context;
+    use object::{Object, ObjectSegment, ObjectSymbol};
+    use std::ffi::CStr;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+    use std::{fs, io};
+
+    pub enum Arg<'a> {
+        Int(u64),
+        Str(&'a CStr),
+    }
+
+    pub fn is_libc(path: &Path) -> bool {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        // dlopen is in libdl before glibc 2.34
+        ["libc.so", "libc-", "libdl", "ld-musl"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    }
+
+    /// Find the address of `name` in the objects mapped by `pid`.
+    pub fn lookup(pid: i32, name: &str, filter: impl Fn(&Path) -> bool) -> anyhow::Result<u64> {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+            .with_context(|| format!("read maps of {} failed", pid))?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) as u64 };
+
+        // start offset dev inode path
+        let objects = maps.lines().filter_map(|line| {
+            let mut iter = line.split_ascii_whitespace();
+            let (start, _) = iter.next()?.split_once('-')?;
+            let offset = iter.nth(1)?;
+            let path = iter.nth(2)?;
+
+            let start = u64::from_str_radix(start, 16).ok()?;
+            let offset = u64::from_str_radix(offset, 16).ok()?;
+            (offset == 0 && path.starts_with('/')).then_some((start, Path::new(path)))
+        });
+
+        for (start, path) in objects.filter(|(_, path)| filter(path)) {
+            let Ok(fd) = fs::File::open(path) else {
+                continue;
+            };
+            let buf = unsafe { memmap2::Mmap::map(&fd)? };
+            let obj = object::File::parse(buf.as_ref())?;
+
+            let Some(sym) = obj
+                .dynamic_symbols()
+                .find(|sym| sym.is_definition() && sym.name() == Ok(name))
+            else {
+                continue;
+            };
+
+            let first = obj.segments().map(|seg| seg.address()).min().unwrap_or(0);
+            let base = start - (first & !(page_size - 1));
+
+            return Ok(base + sym.address());
+        }
+
+        anyhow::bail!("not found `{}` in {}", name, pid)
+    }
+
+    pub struct Tracee {
+        pid: i32,
+        mem: fs::File,
+    }
+
+    impl Tracee {
+        /// Stop the main thread of `pid`, the other threads keep running.
+        pub fn attach(pid: i32) -> anyhow::Result<Tracee> {
+            unsafe {
+                if libc::ptrace(libc::PTRACE_SEIZE as _, pid, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error())
+                        .with_context(|| format!("ptrace attach {} failed", pid));
+                }
+            }
+
+            // owns the tracee from here, detached on drop
+            let mem = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(format!("/proc/{}/mem", pid));
+            let tracee = Tracee {
+                pid,
+                mem: match mem {
+                    Ok(mem) => mem,
+                    Err(err) => {
+                        unsafe {
+                            libc::ptrace(libc::PTRACE_DETACH as _, pid, 0, 0);
+                        }
+                        return Err(err).context("open tracee memory failed");
+                    }
+                },
+            };
+
+            unsafe {
+                if libc::ptrace(libc::PTRACE_INTERRUPT as _, pid, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error()).context("ptrace interrupt failed");
+                }
+            }
+
+            loop {
+                let status = tracee.wait()?;
+                if status >> 16 == libc::PTRACE_EVENT_STOP {
+                    break;
+                }
+
+                // a signal arrived first, deliver it
+                tracee.cont(libc::WSTOPSIG(status))?;
+            }
+
+            Ok(tracee)
+        }
+
+        fn wait(&self) -> anyhow::Result<i32> {
+            let mut status = 0;
+
+            unsafe {
+                if libc::waitpid(self.pid, &mut status, libc::__WALL) < 0 {
+                    return Err(io::Error::last_os_error()).context("waitpid failed");
+                }
+            }
+
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                anyhow::bail!("process {} exited", self.pid);
+            }
+
+            Ok(status)
+        }
+
+        fn cont(&self, sig: i32) -> anyhow::Result<()> {
+            unsafe {
+                if libc::ptrace(libc::PTRACE_CONT as _, self.pid, 0, sig) != 0 {
+                    return Err(io::Error::last_os_error()).context("ptrace cont failed");
+                }
+            }
+
+            Ok(())
+        }
+
+        fn get_regs(&self) -> anyhow::Result<arch::Regs> {
+            let mut regs: arch::Regs = unsafe { std::mem::zeroed() };
+            let mut iov = libc::iovec {
+                iov_base: (&mut regs as *mut arch::Regs).cast(),
+                iov_len: std::mem::size_of::<arch::Regs>(),
+            };
+
+            unsafe {
+                if libc::ptrace(
+                    libc::PTRACE_GETREGSET as _,
+                    self.pid,
+                    libc::NT_PRSTATUS,
+                    &mut iov,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error()).context("ptrace getregs failed");
+                }
+            }
+
+            Ok(regs)
+        }
+
+        fn set_regs(&self, regs: &arch::Regs) -> anyhow::Result<()> {
+            let mut iov = libc::iovec {
+                iov_base: (regs as *const arch::Regs).cast_mut().cast(),
+                iov_len: std::mem::size_of::<arch::Regs>(),
+            };
+
+            unsafe {
+                if libc::ptrace(
+                    libc::PTRACE_SETREGSET as _,
+                    self.pid,
+                    libc::NT_PRSTATUS,
+                    &mut iov,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error()).context("ptrace setregs failed");
+                }
+            }
+
+            Ok(())
+        }
+
+        pub fn read_cstr(&self, addr: u64) -> anyhow::Result<String> {
+            let mut buf = vec![0; 1024];
+            let n = self.mem.read_at(&mut buf, addr)?;
+            buf.truncate(n);
+
+            let s = CStr::from_bytes_until_nul(&buf)
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| String::from_utf8_lossy(&buf).into_owned());
+            Ok(s)
+        }
+
+        /// Call `func` in the stopped thread, it returns to address 0
+        /// and the fault brings the thread back to us.
+        pub fn call(&self, func: u64, args: &[Arg]) -> anyhow::Result<u64> {
+            let saved = self.get_regs()?;
+            let result = self.call_with(&saved, func, args);
+
+            // an interrupted syscall is restarted with the original registers
+            self.set_regs(&saved)?;
+
+            result
+        }
+
+        fn call_with(&self, saved: &arch::Regs, func: u64, args: &[Arg]) -> anyhow::Result<u64> {
+            const RED_ZONE: u64 = 256;
+
+            let mut regs = *saved;
+            let mut sp = arch::stack_pointer(&regs) - RED_ZONE;
+
+            let mut values = Vec::new();
+            for arg in args {
+                match arg {
+                    Arg::Int(n) => values.push(*n),
+                    Arg::Str(s) => {
+                        let bytes = s.to_bytes_with_nul();
+                        sp -= bytes.len() as u64;
+                        self.mem.write_all_at(bytes, sp)?;
+                        values.push(sp);
+                    }
+                }
+            }
+            sp &= !15;
+
+            if cfg!(target_arch = "x86_64") {
+                // return address
+                sp -= 8;
+                self.mem.write_all_at(&0u64.to_ne_bytes(), sp)?;
+            }
+
+            arch::prepare(&mut regs, func, &values, sp);
+            self.set_regs(&regs)?;
+            self.cont(0)?;
+
+            loop {
+                let status = self.wait()?;
+                let sig = libc::WSTOPSIG(status);
+
+                if sig == libc::SIGSEGV {
+                    let regs = self.get_regs()?;
+                    if arch::program_counter(&regs) != 0 {
+                        anyhow::bail!("process {} crashed during the call", self.pid);
+                    }
+
+                    return Ok(arch::return_value(&regs));
+                }
+
+                // group stop or another signal, keep going
+                let sig = if status >> 16 != 0 { 0 } else { sig };
+                self.cont(sig)?;
+            }
+        }
+    }
+
+    impl Drop for Tracee {
+        fn drop(&mut self) {
+            unsafe {
+                libc::ptrace(libc::PTRACE_DETACH as _, self.pid, 0, 0);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    mod arch {
+        pub type Regs = libc::user_regs_struct;
+
+        pub fn stack_pointer(regs: &Regs) -> u64 {
+            regs.rsp
+        }
+
+        pub fn program_counter(regs: &Regs) -> u64 {
+            regs.rip
+        }
+
+        pub fn return_value(regs: &Regs) -> u64 {
+            regs.rax
+        }
+
+        pub fn prepare(regs: &mut Regs, func: u64, args: &[u64], sp: u64) {
+            let slots = [
+                &mut regs.rdi,
+                &mut regs.rsi,
+                &mut regs.rdx,
+                &mut regs.rcx,
+                &mut regs.r8,
+                &mut regs.r9,
+            ];
+            for (reg, arg) in slots.into_iter().zip(args) {
+                *reg = *arg;
+            }
+
+            regs.rip = func;
+            regs.rsp = sp;
+            regs.rax = 0;
+            // not in a syscall, don't let the kernel restart it
+            regs.orig_rax = u64::MAX;
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    mod arch {
+        pub type Regs = libc::user_regs_struct;
+
+        pub fn stack_pointer(regs: &Regs) -> u64 {
+            regs.sp
+        }
+
+        pub fn program_counter(regs: &Regs) -> u64 {
+            regs.pc
+        }
+
+        pub fn return_value(regs: &Regs) -> u64 {
+            regs.regs[0]
+        }
+
+        // the kernel already rewinds an interrupted syscall before the stop
+        pub fn prepare(regs: &mut Regs, func: u64, args: &[u64], sp: u64) {
+            for (reg, arg) in regs.regs.iter_mut().zip(args) {
+                *reg = *arg;
+            }
+
+            regs.pc = func;
+            regs.sp = sp;
+            // return address
+            regs.regs[30] = 0;
+        }
+    }
+}
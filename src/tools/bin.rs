@@ -1,11 +1,16 @@
 #![allow(clippy::uninlined_format_args)]
 
+#[path = "../config.rs"]
+mod config;
 #[path = "../layout.rs"]
 mod layout;
 mod util;
 
+mod attach;
 mod convert;
+mod doctor;
 mod filter;
+mod list;
 mod memory;
 mod record;
 
@@ -21,8 +26,11 @@ struct Options {
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
+    Attach(attach::SubCommand),
     Convert(convert::SubCommand),
+    Doctor(doctor::SubCommand),
     Filter(filter::SubCommand),
+    List(list::SubCommand),
     Memory(memory::SubCommand),
     Record(record::SubCommand),
 }
@@ -31,8 +39,11 @@ fn main() -> anyhow::Result<()> {
     let options: Options = argh::from_env();
 
     match options.subcmd {
+        SubCommand::Attach(cmd) => cmd.exec(),
         SubCommand::Convert(cmd) => cmd.exec(),
+        SubCommand::Doctor(cmd) => cmd.exec(),
         SubCommand::Filter(cmd) => cmd.exec(),
+        SubCommand::List(cmd) => cmd.exec(),
         SubCommand::Memory(cmd) => cmd.exec(),
         SubCommand::Record(cmd) => cmd.exec(),
     }
@@ -1,15 +1,16 @@
 mod chrome_trace;
 mod pola;
 
+use crate::config::Config;
 use crate::layout;
+use crate::util::LogReader;
 use anyhow::Context;
 use argh::FromArgs;
 use object::{Object, ObjectSection};
 use std::cell::RefCell;
 use std::collections::{HashMap, hash_map};
-use std::io::Read;
+use std::fs;
 use std::path::PathBuf;
-use std::{fs, io};
 use zerocopy::FromBytes;
 
 /// Convert command
@@ -24,7 +25,7 @@ pub struct SubCommand {
     #[argh(option, short = 's')]
     symbol: Option<PathBuf>,
 
-    /// filter config
+    /// capture config file
     #[argh(option, short = 'c')]
     config: Option<PathBuf>,
 
@@ -37,8 +38,7 @@ pub struct SubCommand {
     output: PathBuf,
 }
 
-#[derive(argh::FromArgValue)]
-#[derive(PartialEq, Eq, Debug, Default)]
+#[derive(argh::FromArgValue, PartialEq, Eq, Debug, Default)]
 enum Type {
     #[default]
     ChromeTrace,
@@ -47,22 +47,19 @@ enum Type {
 
 impl SubCommand {
     pub fn exec(&self) -> anyhow::Result<()> {
-        let log = fs::File::open(&self.path)?;
-        let mut log = io::BufReader::new(log);
-
-        // check sign
-        {
-            let mut sign = [0; layout::SIGN_TRACE.len()];
-            log.read_exact(&mut sign)?;
-            if &sign != layout::SIGN_TRACE {
-                anyhow::bail!("not is sftrace log: {:?}", sign);
-            }
-        }
-
-        let metadata: layout::Metadata = cbor4ii::serde::from_reader(&mut log)?;
+        let (mut log, metadata) = LogReader::open(&self.path)?;
         let pid = metadata.pid.try_into().context("bad pid")?;
 
-        let sympath = self.symbol.as_ref().unwrap_or(&metadata.shlib_path);
+        let config = match self.config.as_ref() {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        let sympath = self
+            .symbol
+            .as_ref()
+            .or(config.object.path.as_ref())
+            .unwrap_or(&metadata.shlib_path);
         let symfd = fs::File::open(sympath)?;
         let symbuf = unsafe { memmap2::Mmap::map(&symfd)? };
         let symobj = object::File::parse(&*symbuf)?;
@@ -73,7 +70,8 @@ impl SubCommand {
 
         let entry_map = <[layout::XRayFunctionEntry]>::ref_from_bytes(xray_buf.as_ref())
             .map_err(|err| anyhow::format_err!("xray_instr_map parse failed: {:?}", err))?;
-        let entry_map = layout::XRayInstrMap(entry_map);
+        let entry_map =
+            layout::XRayInstrMap::new(entry_map).relocate(&symobj, xray_section.address());
 
         if let Ok(Some(build_id)) = symobj.build_id()
             && metadata.shlibid != build_id
@@ -98,10 +96,12 @@ impl SubCommand {
         };
 
         match self.r#type {
-            Type::ChromeTrace =>
-                chrome_trace::PacketWriter::default().convert(&mut log, &mut state, &self.output)?,
-            Type::Pola =>
-                pola::PacketWriter::default().convert(&mut log, &mut state, &self.output)?,
+            Type::ChromeTrace => {
+                chrome_trace::PacketWriter::default().convert(&mut log, &mut state, &self.output)?
+            }
+            Type::Pola => {
+                pola::PacketWriter::default().convert(&mut log, &mut state, &self.output)?
+            }
         }
 
         Ok(())
@@ -117,27 +117,113 @@ struct State<'g> {
     entry_map: layout::XRayInstrMap<'g>,
 }
 
-struct Addr2Line {
+/// Describe the events dropped on a thread by reason and warn about them.
+fn report_dropped(tid: u32, dropped: &layout::DroppedEvent) -> String {
+    let reasons = [
+        (dropped.reentrant, "reentrant"),
+        (dropped.teardown, "teardown"),
+        (dropped.limit, "output size limit"),
+    ]
+    .into_iter()
+    .filter(|(n, _)| *n != 0)
+    .map(|(n, reason)| format!("{} {}", n, reason))
+    .collect::<Vec<_>>()
+    .join(", ");
+    let msg = format!("{} events dropped ({})", dropped.total(), reasons);
+
+    if tid == layout::WRITER_TID {
+        eprintln!("{}", msg);
+    } else {
+        eprintln!("thread {}: {}, its track is incomplete", tid, msg);
+    }
+
+    msg
+}
+
+/// Describe the signal that killed the process and print it.
+fn report_crash(crash: &layout::CrashEvent) -> String {
+    let name = match crash.signal {
+        libc::SIGSEGV => "SIGSEGV".to_owned(),
+        libc::SIGBUS => "SIGBUS".to_owned(),
+        libc::SIGABRT => "SIGABRT".to_owned(),
+        libc::SIGILL => "SIGILL".to_owned(),
+        sig => format!("signal {}", sig),
+    };
+    let msg = match crash.addr {
+        0 => name,
+        addr => format!("{} at {:#x}", name, addr),
+    };
+    eprintln!("process killed by {}", msg);
+    msg
+}
+
+/// Where a panic started unwinding a thread.
+///
+/// Unwinding never hits the exit sleds, so the frames it passed through are
+/// still open when the frame that caught the panic exits.
+#[derive(Clone, Copy, Debug)]
+struct Unwind {
+    time: u64,
+    depth: usize,
+}
+
+impl Unwind {
+    /// Pops the frames unwound above the frame that `exit_func` belongs to,
+    /// returning each of them with the time it should be closed at.
+    fn repair<T: Copy>(
+        &self,
+        stack: &mut Vec<T>,
+        time: u64,
+        exit_func: u64,
+        func: impl Fn(&T) -> u64,
+    ) -> Vec<(T, u64)> {
+        let Some(pos) = stack.iter().rposition(|frame| func(frame) == exit_func) else {
+            return Vec::new();
+        };
+
+        if pos >= self.depth || pos + 1 == stack.len() {
+            return Vec::new();
+        }
+
+        stack
+            .drain(pos + 1..)
+            .enumerate()
+            .rev()
+            .map(|(idx, frame)| {
+                // frames entered after the panic are not older than the marker
+                let time = if pos + 1 + idx < self.depth {
+                    self.time
+                } else {
+                    time
+                };
+                (frame, time)
+            })
+            .collect()
+    }
+}
+
+/// Symbolizes addresses with the debug info, falling back to the symbol table
+pub struct Addr2Line {
     loader: addr2line::Loader,
     cache: RefCell<HashMap<u64, Option<Frame>>>,
 }
 
 #[derive(Clone)]
-struct Frame {
-    name: String,
-    file: Option<String>,
-    line: Option<u32>,
+pub struct Frame {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
 }
 
 impl Addr2Line {
-    fn new(loader: addr2line::Loader) -> Self {
+    pub fn new(loader: addr2line::Loader) -> Self {
         Addr2Line {
             loader,
             cache: RefCell::new(HashMap::new()),
         }
     }
 
-    fn lookup(&self, addr: u64) -> Option<Frame> {
+    pub fn lookup(&self, addr: u64) -> Option<Frame> {
         let mut cache = self.cache.borrow_mut();
 
         match cache.entry(addr) {
@@ -1,15 +1,15 @@
+use super::{State, Unwind, report_crash, report_dropped};
 use crate::layout;
-use crate::util::ArgsData;
+use crate::util::{ArgsData, LogReader};
 use perfetto_trace_proto::{
     DebugAnnotation, EventName, SourceLocation, Trace, TracePacket, debug_annotation, trace_packet,
     track_event,
 };
 use prost::Message;
 use std::collections::{HashMap, HashSet, hash_map};
-use std::io::{BufRead, Write};
+use std::io::Write;
 use std::path::Path;
 use std::{fs, io};
-use super::State;
 
 #[derive(Default)]
 pub struct PacketWriter {
@@ -17,43 +17,71 @@ pub struct PacketWriter {
     addrmap: HashMap<u64, (Option<u64>, Option<u64>)>,
     event_names: HashMap<String, u64>,
     source_locations: HashMap<(String, Option<u32>), u64>,
-    stack: HashMap<u32, Vec<u32>>,
+    stack: HashMap<u32, Vec<Slice>>,
+    unwind: HashMap<u32, Unwind>,
+    /// the CPU each thread is on, if recorded
+    cpu: HashMap<u32, u32>,
     trace: Trace,
 }
 
 impl PacketWriter {
-    pub fn convert(mut self, mut log: &mut io::BufReader<fs::File>, state: &mut State, output: &Path)
-        -> anyhow::Result<()>
-    {
+    pub fn convert(
+        mut self,
+        log: &mut LogReader,
+        state: &mut State,
+        output: &Path,
+    ) -> anyhow::Result<()> {
         let output = fs::File::create(output)?;
         let mut output = flate2::write::GzEncoder::new(output, flate2::Compression::fast());
-                
-        while !log.fill_buf()?.is_empty() {
-            let event: layout::Event<ArgsData, ArgsData, layout::AllocEvent> =
-                cbor4ii::serde::from_reader(&mut log)?;
+
+        while let Some(event) = log.next()? {
+            let event: layout::Event<ArgsData, ArgsData, layout::AllocEvent> = event;
 
             match event.kind {
                 layout::Kind::ENTRY => {
                     let func_id = event.func_id;
-                    self.stack.entry(event.tid).or_default().push(func_id);
-                    self.push_call(state, &event, func_id);
+                    self.stack.entry(event.tid).or_default().push(Slice {
+                        func_id,
+                        cpu_time: event.cpu_time,
+                        counters: event.counters,
+                        cpu: self.cpu.get(&event.tid).copied(),
+                    });
+                    self.push_call(state, &event, func_id, None);
                 }
                 layout::Kind::EXIT | layout::Kind::TAIL_CALL => {
                     let mut has_entry = false;
                     let mut is_empty = false;
+                    let mut unwound = Vec::new();
+                    let mut begin = None;
+
+                    let exit_func = state
+                        .entry_map
+                        .get(state.section_offset, event.func_id)
+                        .function();
 
                     if let Some(stack) = self.stack.get_mut(&event.tid) {
-                        if let Some(entry_func_id) = stack.pop() {
+                        if let Some(unwind) = self.unwind.get(&event.tid) {
+                            unwound = unwind.repair(stack, event.time, exit_func, |slice| {
+                                state
+                                    .entry_map
+                                    .get(state.section_offset, slice.func_id)
+                                    .function()
+                            });
+
+                            if !unwound.is_empty() {
+                                self.unwind.remove(&event.tid);
+                            }
+                        }
+
+                        if let Some(slice) = stack.pop() {
+                            let entry_func_id = slice.func_id;
                             has_entry = true;
+                            begin = Some(slice);
 
                             let entry_func = state
                                 .entry_map
                                 .get(state.section_offset, entry_func_id)
                                 .function();
-                            let exit_func = state
-                                .entry_map
-                                .get(state.section_offset, event.func_id)
-                                .function();
 
                             if entry_func != exit_func {
                                 eprintln!(
@@ -63,6 +91,15 @@ impl PacketWriter {
                             }
                         }
 
+                        // frames below the depth were already there when the panic started
+                        if let Some(unwind) = self.unwind.get_mut(&event.tid) {
+                            unwind.depth = unwind.depth.min(stack.len());
+
+                            if unwind.depth == 0 {
+                                self.unwind.remove(&event.tid);
+                            }
+                        }
+
                         is_empty = stack.is_empty();
                     }
 
@@ -74,7 +111,74 @@ impl PacketWriter {
                         self.stack.remove(&event.tid);
                     }
 
-                    self.push_call(state, &event, event.func_id);
+                    for (slice, time) in unwound {
+                        self.push_unwound(state, event.tid, time, slice.func_id);
+                    }
+
+                    self.push_call(state, &event, event.func_id, begin);
+                }
+                layout::Kind::UNWIND => {
+                    let depth = self.stack.get(&event.tid).map(Vec::len).unwrap_or_default();
+                    self.unwind.insert(
+                        event.tid,
+                        Unwind {
+                            time: event.time,
+                            depth,
+                        },
+                    );
+                    self.push_instant(state, event.tid, event.time, "unwind");
+                }
+                layout::Kind::DROPPED => {
+                    if let Some(dropped) = event.dropped.as_ref() {
+                        let msg = report_dropped(event.tid, dropped);
+                        if event.tid != layout::WRITER_TID {
+                            self.push_instant(state, event.tid, event.time, &msg);
+                        }
+                    }
+                }
+                layout::Kind::CRASH => {
+                    if let Some(crash) = event.crash.as_ref() {
+                        let name = format!("crash: {}", report_crash(crash));
+                        self.push_instant(state, event.tid, event.time, &name);
+                    }
+                }
+                layout::Kind::THREAD_START => {
+                    if let Some(thread) = event.thread.as_ref() {
+                        let name = format!("thread start (os tid {})", thread.os_tid);
+
+                        // draw an arrow from the spawning thread
+                        match (thread.parent, thread.spawn_time) {
+                            (Some(parent), Some(spawn_time)) => {
+                                let flow_id = u64::from(event.tid) + 1;
+                                let spawn = format!("spawn thread {}", event.tid);
+                                self.push_flow_instant(
+                                    state,
+                                    parent,
+                                    spawn_time,
+                                    &spawn,
+                                    vec![flow_id],
+                                    vec![],
+                                );
+                                self.push_flow_instant(
+                                    state,
+                                    event.tid,
+                                    event.time,
+                                    &name,
+                                    vec![],
+                                    vec![flow_id],
+                                );
+                            }
+                            _ => self.push_instant(state, event.tid, event.time, &name),
+                        }
+                    }
+                }
+                layout::Kind::CPU => {
+                    if let Some(cpu) = event.cpu {
+                        self.cpu.insert(event.tid, cpu);
+                    }
+                }
+                layout::Kind::THREAD_END => {
+                    self.push_instant(state, event.tid, event.time, "thread end")
                 }
                 // temp ignore
                 layout::Kind::ALLOC
@@ -89,12 +193,22 @@ impl PacketWriter {
             }
         }
 
+        // threads that never caught their panic
+        for (tid, unwind) in std::mem::take(&mut self.unwind) {
+            let stack = self.stack.remove(&tid).unwrap_or_default();
+
+            for slice in stack.into_iter().rev() {
+                self.push_unwound(state, tid, unwind.time, slice.func_id);
+            }
+        }
+
         self.flush_to(&mut output)?;
         output.flush()?;
 
         Ok(())
     }
-    
+
+    #[allow(clippy::field_reassign_with_default)]
     fn process_uuid(&mut self, global_state: &State) -> u64 {
         let pid = global_state.process_id;
 
@@ -109,21 +223,17 @@ impl PacketWriter {
             packet.first_packet_on_sequence = Some(true);
             packet.sequence_flags = Some(3);
             packet.data = Some(trace_packet::Data::TrackDescriptor(track_desc));
-            packet.optional_trusted_packet_sequence_id
-                = Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
+            packet.optional_trusted_packet_sequence_id =
+                Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
             self.trace.packet.push(packet);
         }
 
         pid as u64
     }
 
-    fn thread_uuid(
-        &mut self,
-        global_state: &State,
-        event: &layout::Event<ArgsData, ArgsData, layout::AllocEvent>,
-    ) -> u64 {
+    #[allow(clippy::field_reassign_with_default)]
+    fn thread_uuid(&mut self, global_state: &State, tid: u32) -> u64 {
         let pid = self.process_uuid(global_state);
-        let tid = event.tid;
 
         if !self.threads.insert(tid) {
             let mut packet = perfetto_trace_proto::TracePacket::default();
@@ -137,8 +247,8 @@ impl PacketWriter {
             });
             packet.data = Some(trace_packet::Data::TrackDescriptor(track_desc));
             packet.sequence_flags = Some(2);
-            packet.optional_trusted_packet_sequence_id
-                = Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
+            packet.optional_trusted_packet_sequence_id =
+                Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
             self.trace.packet.push(packet);
         }
 
@@ -205,18 +315,18 @@ impl PacketWriter {
         state: &mut State,
         event: &layout::Event<ArgsData, ArgsData, layout::AllocEvent>,
         func_id: u32,
+        begin: Option<Slice>,
     ) {
-        let thread_uuid = self.thread_uuid(state, event);
+        let thread_uuid = self.thread_uuid(state, event.tid);
         let entry = state.entry_map.get(state.section_offset, func_id);
         let addr = entry.function();
 
-
         let mut packet = perfetto_trace_proto::TracePacket::default();
         let mut track_event = perfetto_trace_proto::TrackEvent::default();
         packet.timestamp = Some(event.time);
         packet.sequence_flags = Some(2);
-        packet.optional_trusted_packet_sequence_id
-            = Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
+        packet.optional_trusted_packet_sequence_id =
+            Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
         track_event.track_uuid = Some(thread_uuid);
 
         match event.kind {
@@ -232,6 +342,12 @@ impl PacketWriter {
                     .map(|data| to_debug_anno("args", data))
                     .into_iter()
                     .collect();
+
+                if let Some(&cpu) = self.cpu.get(&event.tid) {
+                    track_event
+                        .debug_annotations
+                        .push(to_uint_anno("cpu", cpu.into()));
+                }
             }
             layout::Kind::EXIT | layout::Kind::TAIL_CALL => {
                 track_event.r#type = Some(track_event::Type::SliceEnd.into());
@@ -245,6 +361,40 @@ impl PacketWriter {
                     .map(|data| to_debug_anno("return_value", data))
                     .into_iter()
                     .collect();
+
+                if let Some(begin) = begin {
+                    let delta = |x: Option<u64>, y: Option<u64>| Some(y?.saturating_sub(x?));
+                    let list = [
+                        ("cpu_time", delta(begin.cpu_time, event.cpu_time)),
+                        (
+                            "ctx_switches",
+                            delta(
+                                begin.counters.and_then(|c| c.ctx_switches),
+                                event.counters.and_then(|c| c.ctx_switches),
+                            ),
+                        ),
+                        (
+                            "page_faults",
+                            delta(
+                                begin.counters.and_then(|c| c.page_faults),
+                                event.counters.and_then(|c| c.page_faults),
+                            ),
+                        ),
+                    ];
+
+                    track_event.debug_annotations.extend(
+                        list.into_iter()
+                            .filter_map(|(name, value)| Some(to_uint_anno(name, value?))),
+                    );
+
+                    // migrated while inside
+                    let cpu = self.cpu.get(&event.tid).copied();
+                    if let Some(cpu) = cpu.filter(|&cpu| Some(cpu) != begin.cpu) {
+                        track_event
+                            .debug_annotations
+                            .push(to_uint_anno("exit_cpu", cpu.into()));
+                    }
+                }
             }
             _ => unreachable!(),
         }
@@ -253,6 +403,60 @@ impl PacketWriter {
         self.trace.packet.push(packet);
     }
 
+    fn push_unwound(&mut self, state: &mut State, tid: u32, time: u64, func_id: u32) {
+        let thread_uuid = self.thread_uuid(state, tid);
+        let entry = state.entry_map.get(state.section_offset, func_id);
+        let addr = entry.function();
+
+        let mut packet = perfetto_trace_proto::TracePacket::default();
+        let mut track_event = perfetto_trace_proto::TrackEvent::default();
+        packet.timestamp = Some(time);
+        packet.sequence_flags = Some(2);
+        packet.optional_trusted_packet_sequence_id =
+            Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
+        track_event.track_uuid = Some(thread_uuid);
+        track_event.r#type = Some(track_event::Type::SliceEnd.into());
+        let (name_id, loc_id) = self.frame_info(state, &mut packet, addr);
+        track_event.name_field = name_id.map(track_event::NameField::NameIid);
+        track_event.source_location_field =
+            loc_id.map(track_event::SourceLocationField::SourceLocationIid);
+        track_event.debug_annotations = vec![to_flag_anno("unwound")];
+
+        packet.data = Some(trace_packet::Data::TrackEvent(track_event));
+        self.trace.packet.push(packet);
+    }
+
+    fn push_instant(&mut self, state: &mut State, tid: u32, time: u64, name: &str) {
+        self.push_flow_instant(state, tid, time, name, Vec::new(), Vec::new());
+    }
+
+    fn push_flow_instant(
+        &mut self,
+        state: &mut State,
+        tid: u32,
+        time: u64,
+        name: &str,
+        flow_ids: Vec<u64>,
+        terminating_flow_ids: Vec<u64>,
+    ) {
+        let thread_uuid = self.thread_uuid(state, tid);
+
+        let mut packet = perfetto_trace_proto::TracePacket::default();
+        let mut track_event = perfetto_trace_proto::TrackEvent::default();
+        packet.timestamp = Some(time);
+        packet.sequence_flags = Some(2);
+        packet.optional_trusted_packet_sequence_id =
+            Some(trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(1));
+        track_event.track_uuid = Some(thread_uuid);
+        track_event.r#type = Some(track_event::Type::Instant.into());
+        track_event.name_field = Some(track_event::NameField::Name(name.into()));
+        track_event.flow_ids = flow_ids;
+        track_event.terminating_flow_ids = terminating_flow_ids;
+
+        packet.data = Some(trace_packet::Data::TrackEvent(track_event));
+        self.trace.packet.push(packet);
+    }
+
     // fn push_alloc_event(
     //     &mut self,
     //     state: &mut State,
@@ -266,7 +470,7 @@ impl PacketWriter {
     //             return
     //         };
 
-    //     let thread_uuid = self.thread_uuid(state, event);
+    //     let thread_uuid = self.thread_uuid(state, event.tid);
 
     //     let mut packet = micromegas_perfetto::writer::new_trace_packet();
     //     let mut track_event = micromegas_perfetto::writer::new_track_event();
@@ -324,6 +528,31 @@ impl PacketWriter {
     }
 }
 
+/// An open slice and the counters read at its entry
+#[derive(Clone, Copy)]
+struct Slice {
+    func_id: u32,
+    cpu_time: Option<u64>,
+    counters: Option<layout::Counters>,
+    cpu: Option<u32>,
+}
+
+#[allow(clippy::field_reassign_with_default)]
+fn to_uint_anno(name: &str, value: u64) -> DebugAnnotation {
+    let mut anno = DebugAnnotation::default();
+    anno.name_field = Some(debug_annotation::NameField::Name(name.into()));
+    anno.value = Some(debug_annotation::Value::UintValue(value));
+    anno
+}
+
+#[allow(clippy::field_reassign_with_default)]
+fn to_flag_anno(name: &str) -> DebugAnnotation {
+    let mut anno = DebugAnnotation::default();
+    anno.name_field = Some(debug_annotation::NameField::Name(name.into()));
+    anno.value = Some(debug_annotation::Value::BoolValue(true));
+    anno
+}
+
 #[allow(clippy::field_reassign_with_default)]
 fn to_debug_anno(name: &str, data: &ArgsData) -> DebugAnnotation {
     let mut anno = DebugAnnotation::default();
@@ -1,38 +1,57 @@
-use std::{io, fs};
-use std::path::Path;
-use std::io::BufRead;
-use std::collections::HashMap;
+use super::{State, Unwind, report_crash, report_dropped};
+use crate::layout;
+use crate::util::{ArgsData, LogReader};
 use indexmap::IndexSet;
 use polars::io::parquet;
 use polars::prelude::*;
-use crate::layout;
-use crate::util::ArgsData;
-use super::State;
-
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
 
 #[derive(Default)]
 pub struct PacketWriter {
     stack: HashMap<u32, Vec<(u32, u64)>>,
+    unwind: HashMap<u32, Unwind>,
     funcs: IndexSet<u64>,
     names: Vec<String>,
     files: Vec<String>,
+    threads: BTreeMap<u32, Thread>,
+    /// the CPU each thread is on, if recorded
+    cpu: HashMap<u32, u32>,
+}
+
+/// A row of the threads table
+#[derive(Default)]
+struct Thread {
+    os_tid: Option<u64>,
+    parent: Option<u32>,
+    spawn_time: Option<i64>,
+    start: Option<i64>,
+    end: Option<i64>,
 }
 
 impl PacketWriter {
-    pub fn convert(mut self, mut log: &mut io::BufReader<fs::File>, state: &mut State, path: &Path)
-        -> anyhow::Result<()>
-    {   
+    pub fn convert(
+        mut self,
+        log: &mut LogReader,
+        state: &mut State,
+        path: &Path,
+    ) -> anyhow::Result<()> {
         let packet_schema = {
-            let mut schema = Schema::with_capacity(8);
+            let mut schema = Schema::with_capacity(12);
             schema.with_column("frame_id".into(), DataType::UInt64);
             schema.with_column("parent".into(), DataType::UInt64);
             schema.with_column("tid".into(), DataType::UInt32);
             schema.with_column("func_id".into(), DataType::UInt64);
             schema.with_column("time".into(), DataType::Duration(TimeUnit::Nanoseconds));
             schema.with_column("kind".into(), DataType::UInt32);
+            schema.with_column("cpu_time".into(), DataType::Duration(TimeUnit::Nanoseconds));
+            schema.with_column("ctx_switches".into(), DataType::UInt64);
+            schema.with_column("page_faults".into(), DataType::UInt64);
+            schema.with_column("cpu".into(), DataType::UInt32);
             // FIXME
             // Error: parquet: File out of specification: The number of columns in the row group (8) must be equal to the number of columns in the schema (10)
-            // 
+            //
             // schema.with_column("args".into(), DataType::List(Box::new(DataType::Struct(vec![
             //     Field::new("name".into(), DataType::String),
             //     Field::new("value".into(), DataType::UInt128),
@@ -43,7 +62,7 @@ impl PacketWriter {
             // ]))));
             schema
         };
-        
+
         let output = fs::File::create(path)?;
         let output = parquet::write::ParquetWriter::new(output);
         let mut output = output.batched(&packet_schema)?;
@@ -57,11 +76,10 @@ impl PacketWriter {
                     columns.$key.push($value);
                 )*
             }
-        }        
+        }
 
-        while !log.fill_buf()?.is_empty() {
-            let event: layout::Event<ArgsData, ArgsData, layout::AllocEvent> =
-                cbor4ii::serde::from_reader(&mut log)?;
+        while let Some(event) = log.next()? {
+            let event: layout::Event<ArgsData, ArgsData, layout::AllocEvent> = event;
 
             match event.kind {
                 layout::Kind::ENTRY => {
@@ -80,20 +98,28 @@ impl PacketWriter {
                         && let Some(frame) = state.loader.lookup(entry_func)
                     {
                         self.names.push(frame.name);
-                        self.files.push(format!("{}:{}", frame.file.unwrap_or_default(), frame.line.unwrap_or_default()));
+                        self.files.push(format!(
+                            "{}:{}",
+                            frame.file.unwrap_or_default(),
+                            frame.line.unwrap_or_default()
+                        ));
                     }
-                    
-                    frame_push!{
+
+                    frame_push! {
                         frame_id => frame_id,
                         parent => parent,
                         tid => event.tid,
                         func_id => entry_func,
                         time => AnyValue::Duration(event.time as i64, TimeUnit::Nanoseconds),
                         kind => event.kind.as_u8() as u32,
+                        cpu_time => event.cpu_time.map(|time| time as i64),
+                        ctx_switches => event.counters.and_then(|c| c.ctx_switches),
+                        page_faults => event.counters.and_then(|c| c.page_faults),
+                        cpu => self.cpu.get(&event.tid).copied(),
                         // args => args_data(event.args.as_ref()),
                         // retval => args_data(event.args.as_ref()),
                     }
-                },
+                }
                 layout::Kind::EXIT | layout::Kind::TAIL_CALL => {
                     let mut has_entry = false;
                     let mut is_empty = false;
@@ -103,9 +129,46 @@ impl PacketWriter {
                     let exit_func = state
                         .entry_map
                         .get(state.section_offset, event.func_id)
-                        .function();                    
+                        .function();
 
                     if let Some(stack) = self.stack.get_mut(&event.tid) {
+                        if let Some(unwind) = self.unwind.get(&event.tid) {
+                            let unwound =
+                                unwind.repair(stack, event.time, exit_func, |&(func_id, _)| {
+                                    state
+                                        .entry_map
+                                        .get(state.section_offset, func_id)
+                                        .function()
+                                });
+                            let parents = unwound
+                                .iter()
+                                .skip(1)
+                                .map(|((_, frame_id), _)| *frame_id)
+                                .chain(stack.last().map(|(_, frame_id)| *frame_id))
+                                .collect::<Vec<_>>();
+
+                            if !unwound.is_empty() {
+                                self.unwind.remove(&event.tid);
+                            }
+
+                            for (((func_id, frame_id), time), parent) in
+                                unwound.into_iter().zip(parents)
+                            {
+                                frame_push! {
+                                    frame_id => frame_id,
+                                    parent => parent,
+                                    tid => event.tid,
+                                    func_id => state.entry_map.get(state.section_offset, func_id).function(),
+                                    time => AnyValue::Duration(time as i64, TimeUnit::Nanoseconds),
+                                    kind => layout::Kind::EXIT.as_u8() as u32,
+                                    cpu_time => None,
+                                    ctx_switches => None,
+                                    page_faults => None,
+                                    cpu => None,
+                                }
+                            }
+                        }
+
                         if let Some((entry_func_id, frame_id)) = stack.pop() {
                             has_entry = true;
                             entry_frame_id = Some(frame_id);
@@ -123,6 +186,15 @@ impl PacketWriter {
                             }
                         }
 
+                        // frames below the depth were already there when the panic started
+                        if let Some(unwind) = self.unwind.get_mut(&event.tid) {
+                            unwind.depth = unwind.depth.min(stack.len());
+
+                            if unwind.depth == 0 {
+                                self.unwind.remove(&event.tid);
+                            }
+                        }
+
                         parent = stack.last().map(|(_, frame_id)| *frame_id);
                         is_empty = stack.is_empty();
                     }
@@ -134,19 +206,59 @@ impl PacketWriter {
                     if is_empty {
                         self.stack.remove(&event.tid);
                     }
-                                  
-                    frame_push!{
+
+                    frame_push! {
                         frame_id => entry_frame_id.unwrap_or_default(),
                         parent => parent.unwrap_or_default(),
                         tid => event.tid,
                         func_id => exit_func,
                         time => AnyValue::Duration(event.time as i64, TimeUnit::Nanoseconds),
                         kind => event.kind.as_u8() as u32,
+                        cpu_time => event.cpu_time.map(|time| time as i64),
+                        ctx_switches => event.counters.and_then(|c| c.ctx_switches),
+                        page_faults => event.counters.and_then(|c| c.page_faults),
+                        cpu => self.cpu.get(&event.tid).copied(),
                         // args => args_data(event.args.as_ref()),
                         // retval => args_data(event.return_value.as_ref()),
                     }
-
-                },
+                }
+                layout::Kind::UNWIND => {
+                    let depth = self.stack.get(&event.tid).map(Vec::len).unwrap_or_default();
+                    self.unwind.insert(
+                        event.tid,
+                        Unwind {
+                            time: event.time,
+                            depth,
+                        },
+                    );
+                }
+                layout::Kind::DROPPED => {
+                    if let Some(dropped) = event.dropped.as_ref() {
+                        report_dropped(event.tid, dropped);
+                    }
+                }
+                layout::Kind::CRASH => {
+                    if let Some(crash) = event.crash.as_ref() {
+                        report_crash(crash);
+                    }
+                }
+                layout::Kind::THREAD_START => {
+                    if let Some(thread) = event.thread.as_ref() {
+                        let row = self.threads.entry(event.tid).or_default();
+                        row.os_tid = Some(thread.os_tid);
+                        row.parent = thread.parent;
+                        row.spawn_time = thread.spawn_time.map(|time| time as i64);
+                        row.start = Some(event.time as i64);
+                    }
+                }
+                layout::Kind::CPU => {
+                    if let Some(cpu) = event.cpu {
+                        self.cpu.insert(event.tid, cpu);
+                    }
+                }
+                layout::Kind::THREAD_END => {
+                    self.threads.entry(event.tid).or_default().end = Some(event.time as i64);
+                }
                 // temp ignore
                 layout::Kind::ALLOC
                 | layout::Kind::DEALLOC
@@ -161,6 +273,26 @@ impl PacketWriter {
             }
         }
 
+        // threads that never caught their panic
+        for (tid, unwind) in std::mem::take(&mut self.unwind) {
+            let mut stack = self.stack.remove(&tid).unwrap_or_default();
+
+            while let Some((func_id, frame_id)) = stack.pop() {
+                frame_push! {
+                    frame_id => frame_id,
+                    parent => stack.last().map(|(_, frame_id)| *frame_id).unwrap_or_default(),
+                    tid => tid,
+                    func_id => state.entry_map.get(state.section_offset, func_id).function(),
+                    time => AnyValue::Duration(unwind.time as i64, TimeUnit::Nanoseconds),
+                    kind => layout::Kind::EXIT.as_u8() as u32,
+                    cpu_time => None,
+                    ctx_switches => None,
+                    page_faults => None,
+                    cpu => None,
+                }
+            }
+        }
+
         if !columns.frame_id.is_empty() {
             let df = columns.collect_dataframe()?;
             output.write_batch(&df)?;
@@ -176,7 +308,31 @@ impl PacketWriter {
         let output = fs::File::create(path.with_added_extension("symtab"))?;
         let output = parquet::write::ParquetWriter::new(output);
         output.finish(&mut df)?;
-        
+
+        // export thread table, a thread without end was still running when the trace ended
+        let threads = std::mem::take(&mut self.threads);
+        let column = |name: &str, f: fn(&Thread) -> Option<i64>| {
+            Column::new(name.into(), threads.values().map(f).collect::<Vec<_>>())
+                .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        };
+        let mut df = DataFrame::new_infer_height(vec![
+            Column::new("tid".into(), threads.keys().copied().collect::<Vec<_>>()),
+            Column::new(
+                "os_tid".into(),
+                threads.values().map(|t| t.os_tid).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "parent".into(),
+                threads.values().map(|t| t.parent).collect::<Vec<_>>(),
+            ),
+            column("spawn_time", |t| t.spawn_time)?,
+            column("start", |t| t.start)?,
+            column("end", |t| t.end)?,
+        ])?;
+        let output = fs::File::create(path.with_added_extension("threads"))?;
+        let output = parquet::write::ParquetWriter::new(output);
+        output.finish(&mut df)?;
+
         Ok(())
     }
 }
@@ -189,6 +345,10 @@ struct PacketSchema {
     func_id: Vec<u64>,
     time: Vec<AnyValue<'static>>,
     kind: Vec<u32>,
+    cpu_time: Vec<Option<i64>>,
+    ctx_switches: Vec<Option<u64>>,
+    page_faults: Vec<Option<u64>>,
+    cpu: Vec<Option<u32>>,
     // args: Vec<AnyValue<'a>>,
     // retval: Vec<AnyValue<'a>>
 }
@@ -218,16 +378,30 @@ impl PacketSchema {
             }
         }
 
-        let df = frame_collect!(
-            frame_id,
-            parent,
-            tid,
-            func_id,
-            time,
-            kind,
+        let cpu_time = Column::new(
+            "cpu_time".into(),
+            self.cpu_time.drain(..).collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))?;
+
+        let mut df = frame_collect!(
+            frame_id, parent, tid, func_id, time, kind,
             // args,
             // retval,
         )?;
+        df.with_column(cpu_time)?;
+        df.with_column(Column::new(
+            "ctx_switches".into(),
+            self.ctx_switches.drain(..).collect::<Vec<_>>(),
+        ))?;
+        df.with_column(Column::new(
+            "page_faults".into(),
+            self.page_faults.drain(..).collect::<Vec<_>>(),
+        ))?;
+        df.with_column(Column::new(
+            "cpu".into(),
+            self.cpu.drain(..).collect::<Vec<_>>(),
+        ))?;
 
         Ok(df)
     }
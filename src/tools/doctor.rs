@@ -0,0 +1,532 @@
+use crate::config::{Config, parse_duration};
+use crate::layout;
+use crate::record::search_sftracelib;
+use crate::util::LogReader;
+use anyhow::Context;
+use argh::FromArgs;
+use object::{Object, ObjectSection, ObjectSymbol};
+use serde::de::IgnoredAny;
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+use zerocopy::FromBytes;
+
+/// Check a binary and the environment for the common setup problems
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "doctor")]
+pub struct SubCommand {
+    /// the `libsftrace.so` path
+    #[argh(option)]
+    solib: Option<PathBuf>,
+
+    /// filter file to check against the binary
+    #[argh(option, short = 'f')]
+    filter: Option<PathBuf>,
+
+    /// only inspect the binary, do not run it
+    #[argh(switch)]
+    no_run: bool,
+
+    /// kill the test run after this duration, 10s by default
+    #[argh(option, from_str_fn(duration), default = "Duration::from_secs(10)")]
+    timeout: Duration,
+
+    /// the binary to check
+    #[argh(positional)]
+    path: PathBuf,
+
+    /// arguments of the test run
+    #[argh(positional, greedy)]
+    args: Vec<OsString>,
+}
+
+fn duration(s: &str) -> Result<Duration, String> {
+    parse_duration(s).ok_or_else(|| format!("bad duration: {:?}", s))
+}
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&self, msg: impl AsRef<str>) {
+        println!("ok: {}", msg.as_ref());
+    }
+
+    fn warn(&self, msg: impl AsRef<str>) {
+        println!("warn: {}", msg.as_ref());
+    }
+
+    fn problem(&mut self, msg: impl AsRef<str>, fix: impl AsRef<str>) {
+        self.problems += 1;
+        println!("problem: {}", msg.as_ref());
+        println!("  fix: {}", fix.as_ref());
+    }
+}
+
+/// How the binary gets `libsftrace.so`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Load {
+    /// calls `sftrace_setup`, linked to `libsftrace.so`
+    Linked,
+    Preload,
+}
+
+#[cfg(target_os = "linux")]
+const LIBRARY_PATH_NAME: &str = "LD_LIBRARY_PATH";
+
+#[cfg(target_os = "macos")]
+const LIBRARY_PATH_NAME: &str = "DYLD_LIBRARY_PATH";
+
+impl SubCommand {
+    pub fn exec(self) -> anyhow::Result<()> {
+        let mut report = Report::default();
+
+        let objfd =
+            fs::File::open(&self.path).with_context(|| format!("open {:?} failed", self.path))?;
+        let objbuf = unsafe { memmap2::Mmap::map(&objfd)? };
+        let obj = object::File::parse(&*objbuf)
+            .with_context(|| format!("{:?} is not an object file", self.path))?;
+
+        let functions = check_xray(&mut report, &obj);
+        let build_id = obj.build_id().ok().flatten();
+        match build_id {
+            Some(_) => report.ok("build id found"),
+            None => report.warn(
+                "no build id, traces and filter files can not be checked against this binary",
+            ),
+        }
+
+        let linked = obj
+            .symbols()
+            .chain(obj.dynamic_symbols())
+            .any(|sym| sym.is_undefined() && sym.name().is_ok_and(|name| name == "sftrace_setup"));
+        let load = if linked {
+            report.ok("calls `sftrace_setup`, linked to libsftrace");
+            Load::Linked
+        } else if cfg!(target_os = "linux") {
+            report.ok("not linked to libsftrace, run it with `sftrace record --preload`");
+            Load::Preload
+        } else {
+            report.problem(
+                "does not call `sftrace_setup`",
+                "depend on `sftrace-setup` and call `sftrace_setup::setup()` first in `main`",
+            );
+            Load::Linked
+        };
+
+        let solib = check_solib(&mut report, self.solib.as_deref(), load);
+
+        if let Some(path) = self.filter.as_ref() {
+            check_filter(&mut report, path, build_id, functions.as_ref());
+        }
+
+        match Config::from_env() {
+            Ok(config) if config.output.path().is_some() => report.warn(format!(
+                "the environment already sets the output to {:?}, the test run overrides it",
+                config.output.path().unwrap_or_default()
+            )),
+            Ok(_) => (),
+            Err(err) => report.problem(
+                format!("the SFTRACE_* environment is invalid: {:#}", err),
+                "fix or unset the variable",
+            ),
+        }
+
+        if !self.no_run && functions.is_some() {
+            match solib {
+                Some(solib) => self.test_run(&mut report, &solib, load)?,
+                None => report.warn("libsftrace not found, test run skipped"),
+            }
+        }
+
+        if report.problems != 0 {
+            anyhow::bail!("{} problem(s) found", report.problems);
+        }
+
+        println!("no problem found");
+        Ok(())
+    }
+
+    fn test_run(&self, report: &mut Report, solib: &Path, load: Load) -> anyhow::Result<()> {
+        // private and removed on every return
+        let dir = tempfile::Builder::new()
+            .prefix("sftrace-doctor-")
+            .tempdir()?;
+
+        let mut cmd = Command::new(&self.path);
+        cmd.args(&self.args)
+            .env("SFTRACE_OUTPUT_FILE", dir.path().join("sf.log"))
+            .env(layout::DIAGNOSTIC_ENV, "1")
+            .env_remove("SFTRACE_OUTPUT_DIR")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        if let Some(path) = self.filter.as_ref() {
+            cmd.env("SFTRACE_FILTER", path);
+        }
+
+        let libdir = solib.parent().unwrap_or(Path::new("."));
+        match load {
+            Load::Linked => {
+                let mut path = libdir.as_os_str().to_owned();
+                if let Some(old) = env::var_os(LIBRARY_PATH_NAME)
+                    && !old.is_empty()
+                {
+                    path.push(":");
+                    path.push(old);
+                }
+                cmd.env(LIBRARY_PATH_NAME, path);
+            }
+            Load::Preload => {
+                let mut preload = solib.as_os_str().to_owned();
+                if let Some(old) = env::var_os("LD_PRELOAD")
+                    && !old.is_empty()
+                {
+                    preload.push(":");
+                    preload.push(old);
+                }
+                cmd.env("LD_PRELOAD", preload).env("SFTRACE_PRELOAD", "1");
+            }
+        }
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("spawn {:?} failed", self.path))?;
+
+        // read stderr aside, the pipe must not fill up while waiting
+        let mut stderr = child.stderr.take().context("stderr should be piped")?;
+        let reader = thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = stderr.read_to_string(&mut buf);
+            buf
+        });
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if start.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        let stderr = reader.join().unwrap_or_default();
+
+        match status {
+            Some(status) => report.ok(format!("test run exited with {}", status)),
+            None => report.warn(format!(
+                "test run killed after {:?}, buffered events are lost, pass a shorter workload",
+                self.timeout
+            )),
+        }
+
+        check_stderr(report, &stderr);
+
+        let logs = fs::read_dir(dir.path())?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        match logs.first() {
+            Some(log) => match count_events(log) {
+                Ok(0) => report.warn("the trace has no event, nothing instrumented ran or it was all filtered"),
+                Ok(n) => report.ok(format!("the trace has {} events", n)),
+                Err(err) => report.problem(
+                    format!("the trace can not be read: {:#}", err),
+                    "the process may have died before the writer finished, try `SFTRACE_CRASH_HANDLER=1`",
+                ),
+            },
+            None if status.is_some() => report.problem(
+                "no trace was written",
+                match load {
+                    Load::Linked => "call `sftrace_setup::setup()` before anything else in `main`",
+                    Load::Preload => "check that the binary is the main executable, not a wrapper script",
+                },
+            ),
+            None => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the xray sections, returns the instrumented function addresses if any.
+fn check_xray(report: &mut Report, obj: &object::File) -> Option<HashSet<u64>> {
+    let Some(xray_section) = obj.section_by_name("xray_instr_map") else {
+        report.problem(
+            "no `xray_instr_map` section, the binary is not instrumented",
+            "build with `RUSTFLAGS=\"-Zinstrument-xray=always\"` on nightly, \
+             or `clang -fxray-instrument -fxray-instruction-threshold=1`",
+        );
+        return None;
+    };
+
+    let entries = xray_section.uncompressed_data().ok().and_then(|buf| {
+        <[layout::XRayFunctionEntry]>::ref_from_bytes(buf.as_ref())
+            .ok()
+            .map(|entries| entries.to_vec())
+    });
+    let Some(entries) = entries else {
+        report.problem(
+            "`xray_instr_map` section can not be parsed",
+            "the section is truncated or from an unsupported toolchain, rebuild with a recent one",
+        );
+        return None;
+    };
+
+    let entry_map = layout::XRayInstrMap::new(&entries).relocate(obj, xray_section.address());
+    let fn_index = obj.section_by_name("xray_fn_idx").and_then(|section| {
+        let buf = section.uncompressed_data().ok()?;
+        layout::XRayFunctionIndex::parse(
+            obj,
+            &buf,
+            section.address(),
+            &entry_map,
+            xray_section.address(),
+        )
+    });
+    let functions = entry_map.functions(xray_section.address(), fn_index.as_ref());
+
+    let versions = entries
+        .iter()
+        .map(|entry| entry.version)
+        .collect::<BTreeSet<_>>();
+    let unsupported = entries.iter().filter(|entry| entry.version > 2).count();
+    let unknown_kind = entry_map
+        .iter(xray_section.address())
+        .filter(|entry| entry.kind() > 2)
+        .count();
+
+    if entries.is_empty() {
+        report.problem(
+            "`xray_instr_map` is empty, no function is instrumented",
+            "use `-Zinstrument-xray=always`, the default threshold skips small functions",
+        );
+        return None;
+    }
+
+    report.ok(format!(
+        "{} xray sleds in {} functions, sled versions {:?}",
+        entries.len(),
+        functions.len(),
+        versions
+    ));
+
+    if unsupported != 0 {
+        report.problem(
+            format!("{} sleds have a version newer than 2", unsupported),
+            "these are skipped, report the toolchain version to sftrace",
+        );
+    }
+    if unknown_kind != 0 {
+        report.warn(format!(
+            "{} sleds are of kinds other than entry, exit and tail call, they are skipped",
+            unknown_kind
+        ));
+    }
+
+    Some(functions.iter().map(|func| func.address).collect())
+}
+
+fn check_solib(report: &mut Report, solib: Option<&Path>, load: Load) -> Option<PathBuf> {
+    let projdir = directories::ProjectDirs::from("", "", "sftrace");
+    let solib = match (solib, projdir.as_ref()) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, Some(projdir)) => match search_sftracelib(projdir.data_dir()) {
+            Ok(path) => path,
+            Err(err) => {
+                report.problem(
+                    format!("libsftrace {:#}", err),
+                    format!(
+                        "copy it to {} or next to the sftrace binary, or pass --solib",
+                        projdir.data_dir().display()
+                    ),
+                );
+                return None;
+            }
+        },
+        (None, None) => {
+            report.problem("no home directory to search libsftrace in", "pass --solib");
+            return None;
+        }
+    };
+
+    let exports = fs::read(&solib).ok().and_then(|buf| {
+        let obj = object::File::parse(&*buf).ok()?;
+        let names = obj
+            .exports()
+            .ok()?
+            .iter()
+            .map(|export| String::from_utf8_lossy(export.name()).into_owned())
+            .collect::<HashSet<_>>();
+        Some(names)
+    });
+    match exports {
+        Some(names) if names.contains("sftrace_setup") => {
+            report.ok(format!("libsftrace at {}", solib.display()))
+        }
+        Some(_) => {
+            report.problem(
+                format!("{} does not export `sftrace_setup`", solib.display()),
+                "point --solib at the `libsftrace.so` built from this repository",
+            );
+            return None;
+        }
+        None => {
+            report.problem(
+                format!("{} can not be read as a shared object", solib.display()),
+                "rebuild it with `cargo build` and pass its path with --solib",
+            );
+            return None;
+        }
+    }
+
+    // the dynamic loader has to find it on its own when the binary is run directly
+    if load == Load::Linked {
+        let found = env::var_os(LIBRARY_PATH_NAME).is_some_and(|paths| {
+            env::split_paths(&paths).any(|dir| {
+                solib
+                    .file_name()
+                    .is_some_and(|name| dir.join(name).is_file())
+            })
+        });
+        if !found {
+            report.warn(format!(
+                "{} does not contain libsftrace, run the binary with `{}={}` or through `sftrace record`",
+                LIBRARY_PATH_NAME,
+                LIBRARY_PATH_NAME,
+                solib.parent().unwrap_or(Path::new(".")).display()
+            ));
+        }
+    }
+
+    Some(solib)
+}
+
+fn check_filter(
+    report: &mut Report,
+    path: &Path,
+    build_id: Option<&[u8]>,
+    functions: Option<&HashSet<u64>>,
+) {
+    let regenerate = "regenerate it with `sftrace filter -p <binary> -o <filter> ...` \
+                      after every build";
+
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(err) => {
+            report.problem(
+                format!("read filter {:?} failed: {}", path, err),
+                regenerate,
+            );
+            return;
+        }
+    };
+    let map = match layout::FilterMap::parse(&buf, build_id) {
+        Ok(map) => map,
+        Err(err) => {
+            report.problem(format!("filter {:?}: {:#}", path, err), regenerate);
+            return;
+        }
+    };
+
+    // a binary without build id can not tell, look for functions that are gone
+    let marks = map.marks();
+    let stale = functions.map_or(0, |functions| {
+        marks
+            .iter()
+            .filter(|mark| !functions.contains(&mark.addr()))
+            .count()
+    });
+
+    if stale != 0 {
+        report.problem(
+            format!(
+                "{} of {} functions of the filter are not instrumented in this binary",
+                stale,
+                marks.len()
+            ),
+            regenerate,
+        );
+    } else {
+        report.ok(format!("filter matches, {} functions", marks.len()));
+    }
+}
+
+/// Read the diagnostics of the recorder and the loader message in the test run output.
+fn check_stderr(report: &mut Report, stderr: &str) {
+    let mut patched = None;
+
+    for line in stderr.lines() {
+        if line.contains("error while loading shared libraries") && line.contains("sftrace") {
+            report.problem(
+                line.trim(),
+                format!(
+                    "run it with `{}` pointing at the directory of libsftrace",
+                    LIBRARY_PATH_NAME
+                ),
+            );
+            continue;
+        }
+
+        let Some(diagnostic) = line
+            .strip_prefix(layout::DIAGNOSTIC_PREFIX)
+            .and_then(|json| serde_json::from_str::<layout::Diagnostic>(json).ok())
+        else {
+            continue;
+        };
+
+        match diagnostic {
+            layout::Diagnostic::UnlockFailed { error } => report.problem(
+                format!("the text segment can not be made writable: {}", error),
+                "SELinux `deny_execmem` or PaX MPROTECT refuses it, \
+                 allow it for this binary, e.g. `setsebool deny_execmem 0` or `paxctl -m <binary>`",
+            ),
+            layout::Diagnostic::BuildIdMismatch => report.problem(
+                "the build id of the loaded binary does not match the file",
+                "the file on disk changed after the process started, rerun after the build finished",
+            ),
+            layout::Diagnostic::InvalidSleds { count } => report.warn(format!(
+                "{} xray sleds do not match the expected pattern, skipped",
+                count
+            )),
+            layout::Diagnostic::NoTrigger => report.problem(
+                "no trigger function is patched, nothing will be recorded",
+                "check the trigger regex of the filter file or `record.triggers`",
+            ),
+            layout::Diagnostic::Patched { entry, .. } => patched = Some(entry),
+        }
+    }
+
+    match patched {
+        Some(0) => report.problem(
+            "no entry sled was patched",
+            "the filter excludes every function, or the sleds did not match, see above",
+        ),
+        Some(n) => report.ok(format!("{} entry sleds patched", n)),
+        None => report.warn("the recorder did not report any patched sled"),
+    }
+}
+
+fn count_events(path: &Path) -> anyhow::Result<usize> {
+    let (mut log, _) = LogReader::open(path)?;
+    let mut n = 0;
+    while let Some(event) = log.next::<layout::Event<IgnoredAny, IgnoredAny, IgnoredAny>>()? {
+        if matches!(
+            event.kind,
+            layout::Kind::ENTRY | layout::Kind::EXIT | layout::Kind::TAIL_CALL
+        ) {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
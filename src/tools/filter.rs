@@ -1,11 +1,12 @@
 use crate::layout;
+use anyhow::Context;
 use argh::FromArgs;
-use object::Object;
+use object::{Object, ObjectSection};
 use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
-use zerocopy::IntoBytes;
+use zerocopy::{FromBytes, IntoBytes};
 
 /// Filter command
 #[derive(FromArgs, PartialEq, Debug)]
@@ -15,7 +16,7 @@ pub struct SubCommand {
     #[argh(option, short = 'p')]
     path: PathBuf,
 
-    /// filter by list
+    /// filter by list, one name or glob per line
     #[argh(option)]
     list: Option<PathBuf>,
 
@@ -23,9 +24,29 @@ pub struct SubCommand {
     #[argh(option, short = 'r')]
     regex: Option<String>,
 
+    /// filter by glob, `*` matches within a path segment and `**` across them
+    #[argh(option, short = 'g')]
+    glob: Vec<String>,
+
+    /// filter by source file glob, relative globs match any parent directory
+    #[argh(option)]
+    file_glob: Vec<String>,
+
+    /// filter by crate, from the source path or the symbol path
+    #[argh(option, long = "crate")]
+    krate: Vec<String>,
+
+    /// record only inside the functions matching this regex
+    #[argh(option, short = 't')]
+    trigger: Option<String>,
+
     /// filter-file output path
     #[argh(option, short = 'o')]
     output: PathBuf,
+
+    /// match the raw symbol names instead of the demangled names without hash
+    #[argh(switch)]
+    mangled: bool,
 }
 
 impl SubCommand {
@@ -39,40 +60,155 @@ impl SubCommand {
         } else {
             String::new()
         };
-        let listmap = listbuf.lines().collect::<HashSet<_>>();
+        let (listglob, listmap): (Vec<_>, Vec<_>) = listbuf
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .partition(|line| is_glob(line));
+        let listmap = listmap.into_iter().collect::<HashSet<_>>();
+        let maybe_glob = if listglob.is_empty() && self.glob.is_empty() {
+            None
+        } else {
+            let globs = listglob
+                .into_iter()
+                .chain(self.glob.iter().map(String::as_str))
+                .map(|glob| glob_to_regex(glob, ':'))
+                .collect::<Vec<_>>();
+            Some(regex::Regex::new(&format!("^(?:{})$", globs.join("|")))?)
+        };
+        let maybe_file_glob = if self.file_glob.is_empty() {
+            None
+        } else {
+            let globs = self
+                .file_glob
+                .iter()
+                .map(|glob| match glob.strip_prefix('/') {
+                    Some(glob) => format!("/{}", glob_to_regex(glob, '/')),
+                    None => format!("(?:.*/)?{}", glob_to_regex(glob, '/')),
+                })
+                .collect::<Vec<_>>();
+            Some(regex::Regex::new(&format!("^(?:{})$", globs.join("|")))?)
+        };
+        let crates = self
+            .krate
+            .iter()
+            .map(|name| name.replace('-', "_"))
+            .collect::<HashSet<_>>();
+
+        // source locations are only needed by the file and crate rules
+        let maybe_loader = if maybe_file_glob.is_some() || !crates.is_empty() {
+            let loader = addr2line::Loader::new(&self.path)
+                .map_err(|err| anyhow::format_err!("parse symbol failed: {:?}", err))?;
+            Some(loader)
+        } else {
+            None
+        };
+
+        let xray_section = obj
+            .section_by_name("xray_instr_map")
+            .context("not found xray_instr_map section")?;
+        let xray_buf = xray_section.uncompressed_data()?;
+        let entry_map = <[layout::XRayFunctionEntry]>::ref_from_bytes(xray_buf.as_ref())
+            .map_err(|err| anyhow::format_err!("xray_instr_map parse failed: {:?}", err))?;
+        let entry_map = layout::XRayInstrMap::new(entry_map).relocate(&obj, xray_section.address());
+
+        let fn_index = obj.section_by_name("xray_fn_idx").and_then(|section| {
+            let buf = section.uncompressed_data().ok()?;
+            layout::XRayFunctionIndex::parse(
+                &obj,
+                &buf,
+                section.address(),
+                &entry_map,
+                xray_section.address(),
+            )
+        });
+        let functions = entry_map.functions(xray_section.address(), fn_index.as_ref());
 
         let symmap = obj.symbol_map();
         let mut map = Vec::new();
+        let mut sleds = 0;
 
         let maybe_regex = if let Some(s) = self.regex.as_ref() {
             Some(regex::Regex::new(s)?)
         } else {
             None
         };
+        let maybe_trigger = if let Some(s) = self.trigger.as_ref() {
+            Some(regex::Regex::new(s)?)
+        } else {
+            None
+        };
+
+        for func in &functions {
+            let Some(sym) = symmap.get(func.address) else {
+                continue;
+            };
+            let demangled = addr2line::demangle_auto(sym.name().into(), None);
+            let name = if self.mangled {
+                sym.name()
+            } else {
+                &*demangled
+            };
 
-        for sym in symmap.symbols() {
             let mut hint = false;
 
-            if listmap.contains(sym.name())
+            // raw names also match, as in the lists written before demangling
+            if listmap.contains(name)
+                || listmap.contains(sym.name())
                 || maybe_regex
                     .as_ref()
-                    .filter(|re| re.is_match(sym.name()))
+                    .filter(|re| re.is_match(name))
                     .is_some()
+                || maybe_glob.as_ref().filter(|re| re.is_match(name)).is_some()
+            {
+                hint = true;
+            }
+
+            if let Some(loader) = maybe_loader.as_ref() {
+                let file = function_file(loader, func.address);
+
+                if let Some(re) = maybe_file_glob.as_ref()
+                    && file.is_some_and(|file| re.is_match(file))
+                {
+                    hint = true;
+                }
+
+                if !crates.is_empty()
+                    && file
+                        .and_then(crate_from_path)
+                        .or_else(|| crate_from_symbol(&demangled))
+                        .is_some_and(|name| crates.contains(&name))
+                {
+                    hint = true;
+                }
+            }
+
+            let mut flag = layout::FuncFlag::empty();
+            if maybe_trigger
+                .as_ref()
+                .filter(|re| re.is_match(name))
+                .is_some()
             {
                 hint = true;
+                flag |= layout::FuncFlag::TRIGGER;
             }
 
             if hint {
-                let mark =
-                    layout::FilterMark::new(sym.address(), layout::FuncFlag::empty()).unwrap();
+                let mark = layout::FilterMark::new(func.address, flag).unwrap();
                 map.push(mark);
+                sleds += func.sleds.len();
             }
         }
 
         map.sort_by_key(|mark| mark.addr());
         map.dedup();
 
-        println!("done {:?}", map.len());
+        println!(
+            "done {:?} of {:?} functions, {:?} sleds",
+            map.len(),
+            functions.len(),
+            sleds
+        );
 
         let mut output = fs::File::create(&self.output)?;
         let hash = obj
@@ -83,9 +219,170 @@ impl SubCommand {
             .unwrap_or_default();
         output.write_all(layout::SIGN_FILTE)?;
         output.write_all(&hash.to_ne_bytes())?;
-        output.write_all(layout::FilterMode::FILTER.as_bytes())?;
+        // only triggers, keep the other functions
+        let mode = if self.trigger.is_some()
+            && self.list.is_none()
+            && self.regex.is_none()
+            && self.glob.is_empty()
+            && self.file_glob.is_empty()
+            && self.krate.is_empty()
+        {
+            layout::FilterMode::MARK
+        } else {
+            layout::FilterMode::FILTER
+        };
+        output.write_all(mode.as_bytes())?;
         output.write_all(map.as_bytes())?;
 
         Ok(())
     }
 }
+
+/// The source file of the function at `addr`. The line table at the address may
+/// point into code inlined in the prologue, the outermost frame is the function itself.
+fn function_file(loader: &addr2line::Loader, addr: u64) -> Option<&str> {
+    let mut outermost = None;
+    if let Ok(mut frames) = loader.find_frames(addr) {
+        while let Ok(Some(frame)) = frames.next() {
+            outermost = Some(frame);
+        }
+    }
+
+    match outermost.and_then(|frame| frame.location) {
+        Some(location) => location.file,
+        None => loader.find_location(addr).ok().flatten()?.file,
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// `**` matches anything, `*` anything within a path segment, `?` one character but `sep`.
+fn glob_to_regex(pattern: &str, sep: char) -> String {
+    let mut re = String::with_capacity(pattern.len() * 2);
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => re.push_str(".*"),
+            '*' => re.push_str(&format!("[^{}]*", sep)),
+            '?' => re.push_str(&format!("[^{}]", sep)),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re
+}
+
+/// The directory above the last `src`, such as `crates/engine/src/lib.rs`,
+/// or `regex-1.11.1/src/lib.rs` in the cargo registry.
+fn crate_from_path(file: &str) -> Option<String> {
+    let parts = file.split('/').collect::<Vec<_>>();
+    let pos = parts.iter().rposition(|part| *part == "src")?;
+    let dir = *parts.get(pos.checked_sub(1)?)?;
+    // the version may have dashes too, as in `1.0.0-beta.1`
+    let name = dir
+        .match_indices('-')
+        .map(|(idx, _)| &dir[..idx])
+        .find(|name| {
+            let version = &dir[name.len() + 1..];
+            version.starts_with(|c: char| c.is_ascii_digit()) && version.contains('.')
+        })
+        .unwrap_or(dir);
+    (!name.is_empty()).then(|| name.replace('-', "_"))
+}
+
+/// The first segment of the demangled path, `<engine::Db as core::ops::Drop>::drop` is `engine`.
+fn crate_from_symbol(name: &str) -> Option<String> {
+    let name = name.trim_start_matches(['<', '&']);
+    let name = name.strip_prefix("mut ").unwrap_or(name);
+    let name = name.strip_prefix("dyn ").unwrap_or(name);
+    let (krate, _) = name.split_once("::")?;
+    (!krate.is_empty() && !krate.contains([' ', '<', '>', '(', '&', '*'])).then(|| krate.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, sep: char) -> regex::Regex {
+        regex::Regex::new(&format!("^(?:{})$", glob_to_regex(pattern, sep))).unwrap()
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let re = glob("mycrate::*", ':');
+        assert!(re.is_match("mycrate::open"));
+        assert!(!re.is_match("mycrate::storage::open"));
+
+        let re = glob("mycrate::**", ':');
+        assert!(re.is_match("mycrate::open"));
+        assert!(re.is_match("mycrate::storage::open"));
+        assert!(!re.is_match("other::open"));
+
+        let re = glob("mycrate::ope?", ':');
+        assert!(re.is_match("mycrate::open"));
+        assert!(!re.is_match("mycrate::ope:"));
+
+        let re = glob("<mycrate::Db as mycrate::Store>::*", ':');
+        assert!(re.is_match("<mycrate::Db as mycrate::Store>::get"));
+        assert!(!re.is_match("<mycrate::Db as mycrate::Other>::get"));
+
+        let re = glob("src/*.rs", '/');
+        assert!(re.is_match("src/lib.rs"));
+        assert!(!re.is_match("src/storage/mod.rs"));
+        assert!(!re.is_match("src/libxrs"));
+
+        let re = glob("src/**", '/');
+        assert!(re.is_match("src/storage/mod.rs"));
+        assert!(!glob("s?c/lib.rs", '/').is_match("s/c/lib.rs"));
+    }
+
+    #[test]
+    fn test_crate_from_path() {
+        let krate = |file| crate_from_path(file);
+        assert_eq!(
+            krate("/work/crates/engine/src/lib.rs").as_deref(),
+            Some("engine")
+        );
+        assert_eq!(
+            krate("/work/my-app/src/bin/main.rs").as_deref(),
+            Some("my_app")
+        );
+        assert_eq!(
+            krate("/cargo/registry/src/index/regex-1.11.1/src/lib.rs").as_deref(),
+            Some("regex")
+        );
+        assert_eq!(
+            krate("/cargo/registry/src/index/serde-json-1.0.140/src/de.rs").as_deref(),
+            Some("serde_json")
+        );
+        assert_eq!(
+            krate("/cargo/registry/src/index/foo-2.0.0-rc.1/src/lib.rs").as_deref(),
+            Some("foo")
+        );
+        assert_eq!(krate("/work/x86-64/src/lib.rs").as_deref(), Some("x86_64"));
+        assert_eq!(krate("/usr/include/stdio.h"), None);
+        assert_eq!(krate("src/lib.rs"), None);
+    }
+
+    #[test]
+    fn test_crate_from_symbol() {
+        let krate = |name| crate_from_symbol(name);
+        assert_eq!(krate("engine::open").as_deref(), Some("engine"));
+        assert_eq!(
+            krate("<engine::Db as core::ops::Drop>::drop").as_deref(),
+            Some("engine")
+        );
+        assert_eq!(
+            krate("<&mut engine::Db as core::fmt::Debug>::fmt").as_deref(),
+            Some("engine")
+        );
+        assert_eq!(
+            krate("<dyn engine::Store as core::fmt::Debug>::fmt").as_deref(),
+            Some("engine")
+        );
+        assert_eq!(krate("<u8 as core::fmt::Debug>::fmt"), None);
+        assert_eq!(krate("<[u8] as core::fmt::Debug>::fmt"), None);
+        assert_eq!(krate("main"), None);
+    }
+}
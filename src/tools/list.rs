@@ -0,0 +1,220 @@
+use crate::convert::Addr2Line;
+use crate::layout;
+use anyhow::Context;
+use argh::FromArgs;
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use zerocopy::FromBytes;
+
+/// List instrumented functions
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+pub struct SubCommand {
+    /// object file path
+    #[argh(positional)]
+    path: PathBuf,
+
+    /// only the functions whose demangled or symbol name matches this regex
+    #[argh(option, short = 'r')]
+    regex: Option<String>,
+
+    /// sort by addr (default), name, size or sleds
+    #[argh(option, short = 's', default = "Sort::Addr")]
+    sort: Sort,
+
+    /// print a JSON array instead of a table
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Sort {
+    Addr,
+    Name,
+    Size,
+    Sleds,
+}
+
+impl argh::FromArgValue for Sort {
+    fn from_arg_value(value: &str) -> Result<Self, String> {
+        match value {
+            "addr" => Ok(Sort::Addr),
+            "name" => Ok(Sort::Name),
+            "size" => Ok(Sort::Size),
+            "sleds" => Ok(Sort::Sleds),
+            _ => Err(format!("unknown sort key: {:?}", value)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Function {
+    name: String,
+    symbol: Option<String>,
+    address: u64,
+    size: Option<u64>,
+    sleds: usize,
+    kinds: Vec<&'static str>,
+    always_instrument: bool,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+// https://github.com/llvm/llvm-project/blob/llvmorg-20.1.2/llvm/include/llvm/CodeGen/AsmPrinter.h#L342
+fn kind_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "entry",
+        1 => "exit",
+        2 => "tail",
+        3 => "args",
+        4 => "custom",
+        5 => "typed",
+        _ => "unknown",
+    }
+}
+
+impl SubCommand {
+    pub fn exec(&self) -> anyhow::Result<()> {
+        let objfd = fs::File::open(&self.path)?;
+        let objbuf = unsafe { memmap2::Mmap::map(&objfd)? };
+        let obj = object::File::parse(&*objbuf)?;
+
+        let xray_section = obj
+            .section_by_name("xray_instr_map")
+            .context("not found xray_instr_map section")?;
+        let xray_buf = xray_section.uncompressed_data()?;
+        let entry_map = <[layout::XRayFunctionEntry]>::ref_from_bytes(xray_buf.as_ref())
+            .map_err(|err| anyhow::format_err!("xray_instr_map parse failed: {:?}", err))?;
+        let entry_map = layout::XRayInstrMap::new(entry_map).relocate(&obj, xray_section.address());
+
+        let fn_index = obj.section_by_name("xray_fn_idx").and_then(|section| {
+            let buf = section.uncompressed_data().ok()?;
+            layout::XRayFunctionIndex::parse(
+                &obj,
+                &buf,
+                section.address(),
+                &entry_map,
+                xray_section.address(),
+            )
+        });
+        let functions = entry_map.functions(xray_section.address(), fn_index.as_ref());
+
+        let loader = addr2line::Loader::new(&self.path)
+            .map_err(|err| anyhow::format_err!("parse symbol failed: {:?}", err))?;
+        let loader = Addr2Line::new(loader);
+
+        let symmap = obj.symbol_map();
+        let sizes = obj
+            .symbols()
+            .filter(|sym| sym.kind() == SymbolKind::Text && sym.size() != 0)
+            .map(|sym| (sym.address(), sym.size()))
+            .collect::<HashMap<_, _>>();
+
+        let maybe_regex = if let Some(s) = self.regex.as_ref() {
+            Some(regex::Regex::new(s)?)
+        } else {
+            None
+        };
+
+        let mut list = Vec::with_capacity(functions.len());
+        for func in &functions {
+            let symbol = symmap
+                .get(func.address)
+                .filter(|sym| sym.address() == func.address)
+                .map(|sym| sym.name().to_owned());
+            let frame = loader.lookup(func.address);
+            let name = frame
+                .as_ref()
+                .map(|frame| frame.name.clone())
+                .or_else(|| symbol.clone())
+                .unwrap_or_else(|| format!("{:#x}", func.address));
+
+            if let Some(re) = maybe_regex.as_ref()
+                && !re.is_match(&name)
+                && !symbol.as_ref().is_some_and(|sym| re.is_match(sym))
+            {
+                continue;
+            }
+
+            let mut kinds = Vec::new();
+            let mut always_instrument = false;
+            for idx in func.sleds.clone() {
+                let entry = entry_map.get(xray_section.address(), idx);
+                let kind = kind_name(entry.kind());
+                if !kinds.contains(&kind) {
+                    kinds.push(kind);
+                }
+                always_instrument |= entry.always_instrument();
+            }
+
+            list.push(Function {
+                name,
+                symbol,
+                address: func.address,
+                size: sizes.get(&func.address).copied(),
+                sleds: func.sleds.len(),
+                kinds,
+                always_instrument,
+                file: frame.as_ref().and_then(|frame| frame.file.clone()),
+                line: frame.as_ref().and_then(|frame| frame.line),
+            });
+        }
+
+        match self.sort {
+            Sort::Addr => list.sort_by_key(|func| func.address),
+            Sort::Name => list.sort_by(|a, b| a.name.cmp(&b.name)),
+            Sort::Size => list.sort_by_key(|func| std::cmp::Reverse(func.size)),
+            Sort::Sleds => list.sort_by_key(|func| std::cmp::Reverse(func.sleds)),
+        }
+
+        // `sftrace list | head`
+        match self.print(&list) {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => (),
+            ret => ret?,
+        }
+
+        Ok(())
+    }
+
+    fn print(&self, list: &[Function]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut stdout = io::BufWriter::new(stdout.lock());
+
+        if self.json {
+            serde_json::to_writer(&mut stdout, list)?;
+            writeln!(stdout)?;
+        } else {
+            writeln!(
+                stdout,
+                "{:<18} {:>8} {:<16} {:<6} {:<40} LOCATION",
+                "ADDRESS", "SIZE", "SLEDS", "ALWAYS", "NAME"
+            )?;
+            for func in list {
+                let size = func.size.map(|n| n.to_string()).unwrap_or_default();
+                let always = if func.always_instrument { "yes" } else { "no" };
+                let location = match (&func.file, func.line) {
+                    (Some(file), Some(line)) => format!("{}:{}", file, line),
+                    (Some(file), None) => file.clone(),
+                    _ => String::new(),
+                };
+                writeln!(
+                    stdout,
+                    "{:<#18x} {:>8} {:<16} {:<6} {:<40} {}",
+                    func.address,
+                    size,
+                    func.kinds.join(","),
+                    always,
+                    func.name,
+                    location
+                )?;
+            }
+        }
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
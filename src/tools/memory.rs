@@ -1,4 +1,5 @@
 use crate::layout;
+use crate::util::LogReader;
 use anyhow::Context;
 use argh::FromArgs;
 use indexmap::IndexMap;
@@ -6,7 +7,7 @@ use object::{Object, ObjectSection, ObjectSymbol};
 use serde::de::IgnoredAny;
 use std::collections::HashMap;
 use std::fs;
-use std::io::{self, BufRead, Read, Write};
+use std::io::{self, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
@@ -65,19 +66,7 @@ macro_rules! try_ {
 
 impl SubCommand {
     pub fn exec(&self) -> anyhow::Result<()> {
-        let log = fs::File::open(&self.path)?;
-        let mut log = io::BufReader::new(log);
-
-        // check sign
-        {
-            let mut sign = [0; layout::SIGN_TRACE.len()];
-            log.read_exact(&mut sign)?;
-            if &sign != layout::SIGN_TRACE {
-                anyhow::bail!("not is sftrace log: {:?}", sign);
-            }
-        }
-
-        let metadata: layout::Metadata = cbor4ii::serde::from_reader(&mut log)?;
+        let (mut log, metadata) = LogReader::open(&self.path)?;
 
         let sympath = self.symbol.as_ref().unwrap_or(&metadata.shlib_path);
         let symfd = fs::File::open(sympath)?;
@@ -90,7 +79,8 @@ impl SubCommand {
 
         let entry_map = <[layout::XRayFunctionEntry]>::ref_from_bytes(xray_buf.as_ref())
             .map_err(|_| anyhow::format_err!("xray_instr_map parse failed"))?;
-        let entry_map = layout::XRayInstrMap(entry_map);
+        let entry_map =
+            layout::XRayInstrMap::new(entry_map).relocate(&symobj, xray_section.address());
 
         let mut memory_analyzer = {
             let mileston_sym = symobj
@@ -103,9 +93,8 @@ impl SubCommand {
             MemoryAnalyzer::new(entry.id())
         };
 
-        while !log.fill_buf()?.is_empty() {
-            let event: layout::Event<IgnoredAny, IgnoredAny, layout::AllocEvent> =
-                cbor4ii::serde::from_reader(&mut log)?;
+        while let Some(event) = log.next()? {
+            let event: layout::Event<IgnoredAny, IgnoredAny, layout::AllocEvent> = event;
 
             memory_analyzer.eat(&event)?;
         }
@@ -204,6 +193,8 @@ struct MemoryAnalyzer {
     milestone_func_id: u32,
     milestones: Vec<u64>,
     threads: HashMap<u32, Vec<u32>>,
+    /// OS tid, start and end time of each thread
+    lifetimes: HashMap<u32, (u64, u64, Option<u64>)>,
     stacklist: Vec<u32>,
     alloc_event: Vec<AllocEvent>,
 }
@@ -241,6 +232,7 @@ impl MemoryAnalyzer {
             milestone_func_id,
             milestones: Vec::new(),
             threads: Default::default(),
+            lifetimes: Default::default(),
             stacklist: Default::default(),
             alloc_event: Default::default(),
         }
@@ -263,6 +255,34 @@ impl MemoryAnalyzer {
             layout::Kind::EXIT | layout::Kind::TAIL_CALL => {
                 self.threads.entry(event.tid).or_default().pop();
             }
+            layout::Kind::UNWIND | layout::Kind::CPU => (),
+            layout::Kind::DROPPED => {
+                if let Some(dropped) = event.dropped.as_ref() {
+                    eprintln!(
+                        "{} events dropped, memory usage is incomplete",
+                        dropped.total()
+                    );
+                }
+            }
+            layout::Kind::CRASH => {
+                if let Some(crash) = event.crash.as_ref() {
+                    eprintln!(
+                        "process crashed with signal {}, memory usage is at the time of the crash",
+                        crash.signal
+                    );
+                }
+            }
+            layout::Kind::THREAD_START => {
+                if let Some(thread) = event.thread.as_ref() {
+                    self.lifetimes
+                        .insert(event.tid, (thread.os_tid, event.time, None));
+                }
+            }
+            layout::Kind::THREAD_END => {
+                if let Some(lifetime) = self.lifetimes.get_mut(&event.tid) {
+                    lifetime.2 = Some(event.time);
+                }
+            }
             layout::Kind::ALLOC
             | layout::Kind::DEALLOC
             | layout::Kind::REALLOC_ALLOC
@@ -490,6 +510,20 @@ impl MemoryAnalyzer {
 
         println!("kind: {}", kind_to_str(ev.kind));
         println!("tid: {}", ev.tid);
+        match self.lifetimes.get(&ev.tid) {
+            Some((os_tid, start, Some(end))) => println!(
+                "thread: os tid {}, ran from {:?} to {:?}",
+                os_tid,
+                Duration::from_nanos(*start),
+                Duration::from_nanos(*end)
+            ),
+            Some((os_tid, start, None)) => println!(
+                "thread: os tid {}, ran from {:?} until the end of the trace",
+                os_tid,
+                Duration::from_nanos(*start)
+            ),
+            None => (),
+        }
         println!(
             "time: {} ({:?})",
             ev.time,
@@ -1,7 +1,9 @@
+use crate::config::Config;
 use anyhow::Context;
 use argh::FromArgs;
 use std::env;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
@@ -13,14 +15,26 @@ pub struct SubCommand {
     #[argh(option)]
     solib: Option<PathBuf>,
 
+    /// capture config file
+    #[argh(option, short = 'c')]
+    config: Option<PathBuf>,
+
     /// filter file
     #[argh(option, short = 'f')]
     filter: Option<PathBuf>,
 
-    /// output log file
+    /// output log file, can contain `%p` `%t` `%e` `%h` `%n`
     #[argh(option, short = 'o')]
     output: Option<PathBuf>,
 
+    /// output directory, the log file defaults to `sf-%e-%p.log` in it
+    #[argh(option, short = 'd')]
+    output_dir: Option<PathBuf>,
+
+    /// load `libsftrace.so` with `LD_PRELOAD`, no `sftrace-setup` needed
+    #[argh(switch)]
+    preload: bool,
+
     /// command
     #[argh(positional, greedy)]
     cmd: Vec<OsString>,
@@ -42,7 +56,7 @@ fn sftracelib() -> String {
     )
 }
 
-fn search_sftracelib(datadir: &Path) -> anyhow::Result<PathBuf> {
+pub fn search_sftracelib(datadir: &Path) -> anyhow::Result<PathBuf> {
     let name = sftracelib();
 
     let path = datadir.join(&name);
@@ -64,7 +78,8 @@ fn search_sftracelib(datadir: &Path) -> anyhow::Result<PathBuf> {
 
 impl SubCommand {
     pub fn exec(self) -> anyhow::Result<()> {
-        use std::os::unix::process::CommandExt;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::process::ExitStatusExt;
 
         #[cfg(target_os = "linux")]
         const LIBRARY_PATH_NAME: &str = "LD_LIBRARY_PATH";
@@ -94,16 +109,60 @@ impl SubCommand {
         let mut iter = self.cmd.iter();
         let exe = iter.next().context("need command")?;
 
-        let output = self.output.clone().unwrap_or_else(|| cwd.join("sf.log"));
+        let config = match self.config.as_ref() {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
 
         let mut cmd = Command::new(exe);
-        cmd.args(iter).env("SFTRACE_OUTPUT_FILE", output);
+        cmd.args(iter);
+
+        if let Some(path) = self.config.as_ref() {
+            cmd.env("SFTRACE_CONFIG", cwd.join(path));
+        }
+
+        if let Some(dir) = self.output_dir.as_ref() {
+            cmd.env("SFTRACE_OUTPUT_DIR", cwd.join(dir));
+        }
+
+        // the config file can also specify the output
+        let has_output = self.output_dir.is_some()
+            || config.output.path().is_some()
+            || env::var_os("SFTRACE_OUTPUT_DIR").is_some();
+        match self.output.as_ref() {
+            Some(output) => {
+                cmd.env("SFTRACE_OUTPUT_FILE", output);
+            }
+            None if !has_output => {
+                cmd.env("SFTRACE_OUTPUT_FILE", cwd.join("sf.log"));
+            }
+            None => (),
+        }
 
         if let Some(path) = self.filter.as_ref() {
             cmd.env("SFTRACE_FILTER", path);
         }
 
-        if env::var_os(LIBRARY_PATH_NAME).is_none() {
+        if self.preload {
+            if !cfg!(target_os = "linux") {
+                anyhow::bail!("--preload is only supported on linux");
+            }
+
+            let solib = match self.solib.as_ref() {
+                Some(p) => p.clone(),
+                None => search_sftracelib(projdir.data_dir())?,
+            };
+
+            let mut preload = solib.into_os_string();
+            if let Some(old) = env::var_os("LD_PRELOAD")
+                && !old.is_empty()
+            {
+                preload.push(":");
+                preload.push(old);
+            }
+
+            cmd.env("LD_PRELOAD", preload).env("SFTRACE_PRELOAD", "1");
+        } else if env::var_os(LIBRARY_PATH_NAME).is_none() {
             let solib = match self.solib.as_ref() {
                 Some(p) => p.clone(),
                 None => search_sftracelib(projdir.data_dir())?,
@@ -113,6 +172,50 @@ impl SubCommand {
             cmd.env(LIBRARY_PATH_NAME, libdir);
         }
 
-        Err(cmd.exec())?
+        // every traced process appends its resolved output path,
+        // in a private directory removed when we are done
+        let tmpdir = tempfile::Builder::new()
+            .prefix("sftrace-record-")
+            .tempdir()?;
+        let list = tmpdir.path().join("output.list");
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&list)?;
+        cmd.env("SFTRACE_OUTPUT_LIST", &list);
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("spawn {:?} failed", exe))?;
+
+        // ctrl-c is for the program, wait for it to exit
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+        }
+        let status = child.wait()?;
+
+        let paths = fs::read(&list).unwrap_or_default();
+        drop(tmpdir);
+
+        for path in paths.split(|&c| c == b'\n').filter(|path| !path.is_empty()) {
+            let path = Path::new(OsStr::from_bytes(path));
+            eprintln!("sftrace: {}", path.display());
+
+            for n in 1.. {
+                let mut rotated = path.as_os_str().to_owned();
+                rotated.push(format!(".{}", n));
+                let rotated = PathBuf::from(rotated);
+
+                if !rotated.is_file() {
+                    break;
+                }
+                eprintln!("sftrace: {}", rotated.display());
+            }
+        }
+
+        let code = status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+        std::process::exit(code)
     }
 }
@@ -1,6 +1,75 @@
+use crate::layout;
 use serde::{Deserialize, Deserializer, de};
-use std::fmt;
+use std::io::{BufRead, Read};
 use std::marker::PhantomData;
+use std::path::Path;
+use std::{fmt, fs, io};
+use zerocopy::{FromZeros, IntoBytes};
+
+/// Reads the events of a sftrace log chunk by chunk.
+pub struct LogReader {
+    log: io::BufReader<fs::File>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl LogReader {
+    pub fn open(path: &Path) -> anyhow::Result<(LogReader, layout::Metadata)> {
+        let log = fs::File::open(path)?;
+        let mut log = io::BufReader::new(log);
+
+        // check sign
+        {
+            let mut sign = [0; layout::SIGN_TRACE.len()];
+            log.read_exact(&mut sign)?;
+            if &sign != layout::SIGN_TRACE {
+                anyhow::bail!("not is sftrace log: {:?}", sign);
+            }
+        }
+
+        let metadata: layout::Metadata = cbor4ii::serde::from_reader(&mut log)?;
+        let reader = LogReader {
+            log,
+            chunk: io::Cursor::new(Vec::new()),
+        };
+
+        Ok((reader, metadata))
+    }
+
+    pub fn next<T: de::DeserializeOwned>(&mut self) -> anyhow::Result<Option<T>> {
+        while self.chunk.position() >= self.chunk.get_ref().len() as u64 {
+            if self.log.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+
+            let mut header = layout::ChunkHeader::new_zeroed();
+            if let Err(err) = self.log.read_exact(header.as_mut_bytes()) {
+                return truncated(err, "chunk header");
+            }
+
+            let buf = self.chunk.get_mut();
+            buf.clear();
+            buf.resize(header.len.get().try_into()?, 0);
+            if let Err(err) = self.log.read_exact(buf) {
+                buf.clear();
+                return truncated(err, "chunk");
+            }
+            self.chunk.set_position(0);
+        }
+
+        let event = cbor4ii::serde::from_reader(&mut self.chunk)?;
+        Ok(Some(event))
+    }
+}
+
+/// A crash can cut the last chunk short, keep the events before it.
+fn truncated<T>(err: io::Error, what: &str) -> anyhow::Result<Option<T>> {
+    if err.kind() != io::ErrorKind::UnexpectedEof {
+        return Err(err.into());
+    }
+
+    eprintln!("truncated {} at the end of the log, ignored", what);
+    Ok(None)
+}
 
 #[derive(Deserialize, Debug, Default)]
 #[serde(transparent)]
@@ -52,3 +121,66 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for VecMap<K, V> {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::IgnoredAny;
+    use std::io::Write;
+
+    #[test]
+    fn test_truncated_log() {
+        let event = |time| layout::Event::<(), (), ()> {
+            tid: 0,
+            func_id: 1,
+            time,
+            kind: layout::Kind::ENTRY,
+            args: None,
+            return_value: None,
+            alloc_event: None,
+            cpu_time: None,
+            counters: None,
+            dropped: None,
+            crash: None,
+            thread: None,
+            cpu: None,
+        };
+        let chunk = |time| {
+            let mut buf = Vec::new();
+            cbor4ii::serde::to_writer(&mut buf, &event(time)).unwrap();
+            let header = layout::ChunkHeader {
+                tid: 0.into(),
+                len: (buf.len() as u32).into(),
+            };
+            [header.as_bytes(), &buf].concat()
+        };
+        let metadata = layout::Metadata {
+            shlibid: Vec::new(),
+            pid: 1,
+            shlib_base: 0,
+            shlib_path: "test".into(),
+        };
+
+        let mut log = layout::SIGN_TRACE.to_vec();
+        cbor4ii::serde::to_writer(&mut log, &metadata).unwrap();
+        log.extend_from_slice(&chunk(1));
+        let whole = log.len();
+        log.extend_from_slice(&chunk(2));
+
+        // cut in the second chunk, then in its header
+        for cut in [log.len() - 1, whole + 3] {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(&log[..cut]).unwrap();
+
+            let (mut reader, _) = LogReader::open(file.path()).unwrap();
+            let mut next = || {
+                reader
+                    .next::<layout::Event<IgnoredAny, IgnoredAny, IgnoredAny>>()
+                    .unwrap()
+            };
+            assert_eq!(next().map(|event| event.time), Some(1));
+            assert!(next().is_none());
+            assert!(next().is_none());
+        }
+    }
+}
@@ -2,6 +2,90 @@ pub fn page_size() -> usize {
     unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) as usize }
 }
 
+/// Map `len` bytes within `i32` reach of `range`.
+#[cfg(target_os = "linux")]
+pub fn mmap_near(range: std::ops::Range<usize>, len: usize) -> Option<*mut u8> {
+    const STEP: usize = 1 << 20;
+
+    let page_size = page_size();
+    let reach = |addr: usize| {
+        let start = range.start.min(addr);
+        let end = range.end.max(addr + len);
+        end - start < i32::MAX as usize
+    };
+
+    for i in 1..(i32::MAX as usize / STEP) {
+        let below = range.start.checked_sub(i * STEP);
+        let above = range.end.checked_add(i * STEP);
+
+        for hint in [below, above].into_iter().flatten() {
+            let hint = hint & !(page_size - 1);
+            let ptr = unsafe {
+                libc::mmap(
+                    hint as *mut libc::c_void,
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+                    -1,
+                    0,
+                )
+            };
+
+            if ptr == libc::MAP_FAILED {
+                continue;
+            }
+
+            // older kernels take it as a hint only
+            if reach(ptr as usize) {
+                return Some(ptr.cast());
+            }
+
+            unsafe {
+                libc::munmap(ptr, len);
+            }
+        }
+    }
+
+    None
+}
+
+/// Name of the current thread, at most 15 bytes on linux
+pub fn thread_name() -> Option<String> {
+    let mut buf = [0u8; 64];
+
+    #[cfg(target_os = "linux")]
+    let ret = unsafe { libc::prctl(libc::PR_GET_NAME, buf.as_mut_ptr()) };
+
+    #[cfg(target_os = "macos")]
+    let ret = unsafe {
+        libc::pthread_getname_np(libc::pthread_self(), buf.as_mut_ptr().cast(), buf.len())
+    };
+
+    if ret != 0 {
+        return None;
+    }
+
+    let name = std::ffi::CStr::from_bytes_until_nul(&buf).ok()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Kernel id of the current thread
+pub fn os_tid() -> u64 {
+    #[cfg(target_os = "linux")]
+    let tid = unsafe { libc::gettid() as u64 };
+
+    #[cfg(target_os = "macos")]
+    let tid = {
+        let mut tid = 0;
+        unsafe {
+            libc::pthread_threadid_np(0, &mut tid);
+        }
+        tid
+    };
+
+    tid
+}
+
 pub fn u64_is_zero(n: &u64) -> bool {
     *n == 0
 }
@@ -28,11 +112,11 @@ impl MProtect {
         };
 
         if !succ {
-            eprintln!(
-                "text segment {:?} unlock failed: {:?}",
-                (addr, size),
-                std::io::Error::last_os_error()
-            );
+            let err = std::io::Error::last_os_error();
+            eprintln!("text segment {:?} unlock failed: {:?}", (addr, size), err);
+            crate::diagnose(crate::layout::Diagnostic::UnlockFailed {
+                error: err.to_string(),
+            });
         }
 
         MProtect {
@@ -59,6 +143,9 @@ impl MProtect {
 
         if !succ {
             eprintln!("text segment {:?} unlock failed: {:?}", (addr, size), ret);
+            crate::diagnose(crate::layout::Diagnostic::UnlockFailed {
+                error: format!("kern_return {}", ret),
+            });
         }
 
         MProtect {